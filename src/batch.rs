@@ -0,0 +1,112 @@
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use reqwest::Client;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tracing::{Level, error};
+
+use crate::{Config, Result, run_with_client, signals};
+
+/// Checks from a manifest to be run concurrently by `hc-runner batch`
+#[derive(Debug)]
+pub struct Batch {
+    pub(crate) configs: Vec<Config>,
+    pub(crate) concurrency: usize,
+    pub verbosity: Level,
+}
+
+#[derive(Debug)]
+struct Outcome {
+    check: String,
+    /// Not set if the check wasn't started because `hc-runner` was asked to
+    /// exit
+    result: Option<Result<u8>>,
+    duration: Duration,
+}
+
+/// Run every check in `batch`, at most `concurrency` at a time, pinging
+/// healthchecks for each independently, then print a summary. Checks that
+/// haven't started when a signal is received are skipped.
+///
+/// # Errors
+/// Returns `1` if any check failed (or could not be run), otherwise `0`
+pub async fn run(batch: Batch) -> Result<u8> {
+    signals::listen()?;
+    let client = Client::new();
+    let semaphore = Arc::new(Semaphore::new(batch.concurrency.max(1)));
+
+    let mut tasks = JoinSet::new();
+    for (idx, config) in batch.configs.into_iter().enumerate() {
+        let client = client.clone();
+        let semaphore = semaphore.clone();
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await;
+            let check = config.check.to_string();
+            let start = Instant::now();
+            let result = if signals::received().is_some() {
+                None
+            } else {
+                Some(run_with_client(config, client).await)
+            };
+            if let Some(Err(e)) = &result {
+                error!("{check}: {e}");
+            }
+            let duration = start.elapsed();
+            (
+                idx,
+                Outcome {
+                    check,
+                    result,
+                    duration,
+                },
+            )
+        });
+    }
+
+    let mut outcomes = Vec::new();
+    while let Some(outcome) = tasks.join_next().await {
+        outcomes.push(outcome?);
+    }
+    outcomes.sort_by_key(|(idx, _)| *idx);
+    let outcomes: Vec<_> = outcomes.into_iter().map(|(_, o)| o).collect();
+
+    print_summary(&outcomes)?;
+
+    let failed = outcomes
+        .iter()
+        .any(|outcome| !matches!(outcome.result, Some(Ok(0))));
+    Ok(u8::from(failed))
+}
+
+fn print_summary(outcomes: &[Outcome]) -> Result<()> {
+    let width = outcomes
+        .iter()
+        .map(|outcome| outcome.check.len())
+        .chain(["CHECK".len()])
+        .max()
+        .unwrap_or_default();
+
+    let mut stdout = io::stdout().lock();
+    writeln!(
+        stdout,
+        "{:width$}  {:>6}  {:>10}",
+        "CHECK", "EXIT", "DURATION"
+    )?;
+    for Outcome {
+        check,
+        result,
+        duration,
+    } in outcomes
+    {
+        let status = match result {
+            Some(Ok(code)) => code.to_string(),
+            Some(Err(_)) => "error".into(),
+            None => "-".into(),
+        };
+        let duration = format!("{:.1}s", duration.as_secs_f64());
+        writeln!(stdout, "{check:width$}  {status:>6}  {duration:>10}")?;
+    }
+    Ok(())
+}
@@ -0,0 +1,128 @@
+use std::collections::VecDeque;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// Which part of the output to keep when it exceeds the body limit
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize, ValueEnum,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Truncation {
+    /// Keep the beginning of the output
+    Head,
+    /// Keep the end of the output
+    Tail,
+    /// Keep the beginning and the end, eliding the middle
+    #[default]
+    HeadTail,
+}
+
+/// How to format the body of the end ping
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize, ValueEnum,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum BodyFormat {
+    /// The captured output
+    #[default]
+    Text,
+    /// A JSON object with the captured output and metadata about the run
+    Json,
+}
+
+/// Which of the command's output streams to send as the ping body
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize, ValueEnum,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum BodySource {
+    Stdout,
+    #[default]
+    Stderr,
+    /// Both streams, interleaved in the order they were received
+    Both,
+}
+
+/// Keeps at most `limit` bytes of output in memory, discarding the rest
+/// according to the `Truncation` strategy.
+#[derive(Debug)]
+pub(crate) struct Capture {
+    head: Vec<u8>,
+    head_limit: usize,
+    tail: VecDeque<u8>,
+    tail_limit: usize,
+    elided: usize,
+}
+
+impl Capture {
+    pub(crate) fn new(limit: usize, truncation: Truncation) -> Self {
+        let (head_limit, tail_limit) = match truncation {
+            Truncation::Head => (limit, 0),
+            Truncation::Tail => (0, limit),
+            Truncation::HeadTail => (limit / 2, limit - limit / 2),
+        };
+        Self {
+            head: Vec::new(),
+            head_limit,
+            tail: VecDeque::new(),
+            tail_limit,
+            elided: 0,
+        }
+    }
+
+    pub(crate) fn push(&mut self, mut data: &[u8]) {
+        let n = data.len().min(self.head_limit - self.head.len());
+        self.head.extend_from_slice(&data[..n]);
+        data = &data[n..];
+
+        self.tail.extend(data);
+        let excess = self.tail.len().saturating_sub(self.tail_limit);
+        self.tail.drain(..excess);
+        self.elided += excess;
+    }
+
+    /// Return the captured output, with a marker showing where (and how
+    /// much) output was discarded. The kept output is trimmed to make room
+    /// for the marker so the result stays within the limit.
+    pub(crate) fn into_body(self) -> Vec<u8> {
+        let Self {
+            mut head,
+            head_limit,
+            tail,
+            tail_limit,
+            mut elided,
+        } = self;
+        let mut tail = Vec::from(tail);
+        if elided == 0 {
+            head.extend(tail);
+            return head;
+        }
+
+        let limit = head_limit + tail_limit;
+        let marker_len = |elided: usize| marker(elided).len();
+        while head.len() + tail.len() + marker_len(elided) > limit
+            && !(head.is_empty() && tail.is_empty())
+        {
+            // Trim from whichever side is adjacent to the marker, favoring
+            // the longer one
+            if head.len() > tail.len() {
+                head.pop();
+            } else {
+                tail.remove(0);
+            }
+            elided += 1;
+        }
+
+        head.extend(marker(elided).as_bytes());
+        head.extend(tail);
+        head
+    }
+}
+
+fn marker(elided: usize) -> String {
+    format!("\n[... {elided} bytes elided by hc-runner ...]\n")
+}
+
+#[cfg(test)]
+mod tests;
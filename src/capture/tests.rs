@@ -0,0 +1,59 @@
+use super::*;
+
+fn capture(limit: usize, truncation: Truncation, chunks: &[&str]) -> String {
+    let mut capture = Capture::new(limit, truncation);
+    for chunk in chunks {
+        capture.push(chunk.as_bytes());
+    }
+    String::from_utf8(capture.into_body()).unwrap()
+}
+
+#[test]
+fn test_under_limit_is_unchanged() {
+    for truncation in
+        [Truncation::Head, Truncation::Tail, Truncation::HeadTail]
+    {
+        assert_eq!(capture(10, truncation, &["foo", "bar"]), "foobar");
+        assert_eq!(capture(6, truncation, &["foo", "bar"]), "foobar");
+    }
+}
+
+#[test]
+fn test_truncate_head() {
+    let body = capture(
+        60,
+        Truncation::Head,
+        &[&"a".repeat(50), "bbbbb", &"c".repeat(50)],
+    );
+    assert!(body.len() <= 60);
+    assert!(body.starts_with("aaaa"));
+    assert!(body.ends_with("bytes elided by hc-runner ...]\n"));
+    assert!(!body.contains("cc"));
+}
+
+#[test]
+fn test_truncate_tail() {
+    let body = capture(60, Truncation::Tail, &[&"a".repeat(100), "bbbbb"]);
+    assert!(body.len() <= 60);
+    assert!(body.starts_with("\n[... "));
+    assert!(body.ends_with("abbbbb"));
+}
+
+#[test]
+fn test_truncate_head_tail() {
+    let body = capture(
+        100,
+        Truncation::HeadTail,
+        &["start", &"a".repeat(500), "end"],
+    );
+    assert!(body.len() <= 100);
+    assert!(body.starts_with("startaaa"));
+    assert!(body.contains("[... "));
+    assert!(body.ends_with("aaaend"));
+}
+
+#[test]
+fn test_elided_count() {
+    let body = capture(0, Truncation::Head, &["foo", "bar"]);
+    assert_eq!(body, marker(6));
+}
@@ -0,0 +1,283 @@
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+use clap::Subcommand;
+use config_rs::{Map, Source, Value, ValueKind};
+use serde::Deserialize;
+use serde::de::{self, Deserializer, Visitor};
+use tracing::Level;
+
+use super::{Check, Cli, Settings, config_file, find_config_file, locations};
+use crate::dry_run::write_settings;
+use crate::{Error, Result, project_dirs, redact};
+
+/// Commented config file written by `hc-runner config init`
+const TEMPLATE: &str = include_str!("template.toml");
+
+#[derive(Clone, Debug, Subcommand)]
+pub(crate) enum ConfigCommand {
+    /// Check the config files for invalid values and unknown keys
+    Check,
+
+    /// Show the value of each setting from the config files and
+    /// environment, and where it came from, with secrets masked
+    Show,
+
+    /// Show where config files are looked for, later overriding earlier
+    Path,
+
+    /// Write a commented config file template (readable only by you) to
+    /// the user's config directory, or to `--config` (ending in `.toml`) if
+    /// given
+    Init {
+        /// Overwrite an existing config file
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+/// Options for `hc-runner config`
+#[derive(Debug)]
+pub struct Inspect {
+    pub(super) command: ConfigCommand,
+    pub(super) cli: Box<Cli>,
+    pub verbosity: Level,
+}
+
+/// The keys of `Settings`, as declared to serde
+pub(super) fn known_keys() -> &'static [&'static str] {
+    /// Captures the fields passed to `deserialize_struct`
+    struct Fields<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de> Deserializer<'de> for Fields<'_> {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(
+            self,
+            _: V,
+        ) -> std::result::Result<V::Value, Self::Error> {
+            Err(de::Error::custom("only structs are supported"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _: &'static str,
+            fields: &'static [&'static str],
+            _: V,
+        ) -> std::result::Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(de::Error::custom("fields captured"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str
+            string bytes byte_buf option unit unit_struct newtype_struct seq
+            tuple tuple_struct map enum identifier ignored_any
+        }
+    }
+
+    let mut fields: &'static [&'static str] = &[];
+    let _ = Settings::deserialize(Fields(&mut fields));
+    fields
+}
+
+/// Problems with the settings in `table`, whose keys are reported relative
+/// to `prefix` (e.g. `checks.backup.`)
+fn check_table(table: Map<String, Value>, prefix: &str) -> Vec<String> {
+    let known = known_keys();
+    let (table, unknown): (Map<_, _>, Map<_, _>) = table
+        .into_iter()
+        .partition(|(key, _)| known.contains(&key.as_str()));
+    let mut problems: Vec<_> = unknown
+        .keys()
+        .map(|key| format!("unknown key `{prefix}{key}`"))
+        .collect();
+
+    match Value::new(None, ValueKind::Table(table)).try_deserialize() {
+        Ok(Settings {
+            log_fd,
+            log_pattern,
+            ..
+        }) => {
+            if log_fd.is_some_and(|fd| fd < 3) {
+                problems
+                    .push(format!("`{prefix}log_fd` must be 3 or greater"));
+            }
+            if let Some(Err(e)) = log_pattern.map(|p| regex::Regex::new(&p)) {
+                problems.push(format!("invalid `{prefix}log_pattern`: {e}"));
+            }
+        }
+        Err(e) => problems.push(format!("{prefix}{e}")),
+    }
+    problems
+}
+
+/// Problems with the config file at `path`, including those of each profile
+pub(super) fn check_file(path: &Path) -> Vec<String> {
+    let file = match config_file(path) {
+        Ok(Some(file)) => file,
+        Ok(None) => return Vec::new(),
+        Err(e) => return vec![e.to_string()],
+    };
+    let mut table = match file.collect() {
+        Ok(table) => table,
+        Err(e) => return vec![e.to_string()],
+    };
+    let mut problems = Vec::new();
+    if let Some(profiles) = table.remove("checks") {
+        match profiles.into_table() {
+            Ok(profiles) => {
+                for (name, profile) in profiles {
+                    match profile.into_table() {
+                        Ok(profile) => problems.extend(check_table(
+                            profile,
+                            &format!("checks.{name}."),
+                        )),
+                        Err(e) => problems.push(format!("checks.{name}: {e}")),
+                    }
+                }
+            }
+            Err(e) => problems.push(format!("checks: {e}")),
+        }
+    }
+    problems.extend(check_table(table, ""));
+    problems
+}
+
+fn check(cli: &Cli) -> Result<u8> {
+    let mut stdout = io::stdout().lock();
+    let files: Vec<_> = locations(cli)?
+        .into_iter()
+        .filter_map(|location| location.found)
+        .collect();
+    if files.is_empty() {
+        writeln!(stdout, "no config files found")?;
+    }
+
+    let mut ok = true;
+    for file in &files {
+        let problems = check_file(file);
+        if problems.is_empty() {
+            writeln!(stdout, "{}: ok", file.display())?;
+        }
+        for problem in &problems {
+            writeln!(stdout, "{}: {problem}", file.display())?;
+        }
+        ok &= problems.is_empty();
+    }
+
+    // The files were fine individually, so any remaining problems are with
+    // the environment (or the selected profile)
+    if ok && let Err(e) = Settings::load(cli) {
+        writeln!(stdout, "{e}")?;
+        ok = false;
+    }
+    Ok(u8::from(!ok))
+}
+
+fn show(cli: &Cli) -> Result<u8> {
+    let settings = Settings::load(cli)?;
+    if let Some(url) = &settings.url {
+        redact::register_ping_key(url);
+    }
+    if let (Some(url), Some(check)) = (
+        &settings.url,
+        Check::from_parts(settings.slug.clone(), settings.uuid)?,
+    ) {
+        redact::register(url, &check);
+    }
+
+    let serde_json::Value::Object(values) = serde_json::to_value(&settings)?
+    else {
+        return Err(Error::Unknown);
+    };
+    let rows: Vec<_> = values
+        .iter()
+        .map(|(key, value)| {
+            let value = match value {
+                serde_json::Value::Null => "-".into(),
+                serde_json::Value::String(value) => value.clone(),
+                value => value.to_string(),
+            };
+            (key.as_str(), value)
+        })
+        .collect();
+
+    let mut out = Vec::new();
+    write_settings(&mut out, &settings.provenance, &rows)?;
+    let out = String::from_utf8_lossy(&out);
+    io::stdout().write_all(redact::redact(&out).as_bytes())?;
+    Ok(0)
+}
+
+fn path(cli: &Cli) -> Result<u8> {
+    let mut stdout = io::stdout().lock();
+    for location in locations(cli)? {
+        writeln!(stdout, "{location}")?;
+    }
+    Ok(0)
+}
+
+fn init(cli: &Cli, force: bool) -> Result<u8> {
+    let path = if let Some(path) = &cli.config {
+        // The template would be read as another format
+        if path.extension() != Some("toml".as_ref()) {
+            return Err(Error::Config(format!(
+                "the template is TOML, so {} must end in `.toml`",
+                path.display()
+            )));
+        }
+        path.clone()
+    } else {
+        let dirs = project_dirs()?;
+        let dir = dirs.config_dir();
+        // Another format would conflict with the template
+        if let Some(existing) = find_config_file(dir)?
+            && existing.extension() != Some("toml".as_ref())
+        {
+            return Err(Error::Config(format!(
+                "{} already exists",
+                existing.display()
+            )));
+        }
+        dir.join("config.toml")
+    };
+    if path.exists() && !force {
+        return Err(Error::Config(format!(
+            "{} already exists, use --force to overwrite it",
+            path.display()
+        )));
+    }
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = fs::File::options()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)?;
+    // `mode` only applies to new files
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(TEMPLATE.as_bytes())?;
+    writeln!(io::stdout(), "wrote {}", path.display())?;
+    Ok(0)
+}
+
+/// Run `hc-runner config`.
+///
+/// # Errors
+/// Returns an error if the config files can't be read or written. Returns
+/// `1` if `check` finds any problems.
+pub fn run(inspect: &Inspect) -> Result<u8> {
+    let cli = &inspect.cli;
+    match inspect.command {
+        ConfigCommand::Check => check(cli),
+        ConfigCommand::Show => show(cli),
+        ConfigCommand::Path => path(cli),
+        ConfigCommand::Init { force } => init(cli, force),
+    }
+}
@@ -1,64 +1,705 @@
 use std::{
+    collections::HashSet,
+    env,
+    ffi::OsString,
+    fmt,
     io::{self, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
-use crate::{Error, Result};
-use clap::Parser;
+use crate::{
+    BodyFormat, BodySource, Error, LockPolicy, Result, Truncation,
+    batch::Batch, history::Query, project_dirs, redact, spool::Flush,
+};
 use clap::builder::NonEmptyStringValueParser;
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use regex::Regex;
 use reqwest::Url;
 use tracing::Level;
+use uuid::Uuid;
+
+pub mod inspect;
+use inspect::{ConfigCommand, Inspect};
 
-use directories::ProjectDirs;
+mod secret;
+use secret::Resolved;
 
 extern crate config as config_rs;
-use config_rs::{Environment, File};
-use serde::Deserialize;
+use config_rs::{
+    Environment, File, FileFormat, FileSourceFile, Map, Source, Value,
+};
+use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Debug, Parser)]
-#[command(author, version, about, long_about)]
+#[allow(clippy::struct_excessive_bools)]
+#[command(
+    author,
+    version,
+    about,
+    long_about,
+    subcommand_negates_reqs(true),
+    subcommand_value_name("SUBCOMMAND"),
+    subcommand_help_heading("Subcommands"),
+    override_usage(
+        "hc-runner [OPTIONS] <--slug <NAME>|--uuid <UUID>|--profile <NAME>> \
+        [--] [COMMAND]...\n       hc-runner [OPTIONS] <SUBCOMMAND>"
+    )
+)]
 struct Cli {
-    #[arg(trailing_var_arg(true), required(true), value_parser=NonEmptyStringValueParser::new())]
+    #[command(subcommand)]
+    pub(crate) subcommand: Option<SubCommand>,
+
+    #[arg(trailing_var_arg(true), required_unless_present("profile"), value_parser=NonEmptyStringValueParser::new())]
     pub(crate) command: Vec<String>,
 
-    /// Specify a config file in non-default location
+    /// Maximum size of the body sent with the end ping; output beyond this
+    /// is discarded according to `--truncate`. Defaults to 100000.
+    #[arg(long, value_name = "BYTES")]
+    pub(crate) body_limit: Option<usize>,
+
+    /// Format of the end ping body: the captured output as-is, or a JSON
+    /// object with the output and metadata about the run. Defaults to `text`.
+    #[arg(long, value_enum)]
+    pub(crate) body_format: Option<BodyFormat>,
+
+    /// Which of the command's output streams to send as the end ping body.
+    /// Defaults to `stderr`.
+    #[arg(long, value_enum)]
+    pub(crate) body_source: Option<BodySource>,
+
+    /// Read this config file after (and overriding) those in the default
+    /// locations
     #[arg(short, long)]
     pub(crate) config: Option<PathBuf>,
 
+    /// Print the resolved configuration (and where each setting came from),
+    /// the URLs that would be pinged, and the command that would be run, then
+    /// exit without running it.
+    #[arg(long)]
+    pub(crate) dry_run: bool,
+
+    /// Number of runs to keep in the local history (see `hc-runner
+    /// history`), or 0 to disable it. Defaults to 1000.
+    #[arg(long, value_name = "N")]
+    pub(crate) history_limit: Option<usize>,
+
+    /// Seconds to wait for SIGTERM to end the command after `--run-timeout`
+    /// before sending SIGKILL. Defaults to 10.
+    #[arg(long, value_name = "SECONDS")]
+    pub(crate) kill_after: Option<u64>,
+
+    /// Prevent overlapping runs of the same check, deciding what to do if
+    /// another run is still in progress.
+    #[arg(long, value_enum, value_name = "POLICY", overrides_with("no_lock"))]
+    pub(crate) lock: Option<LockPolicy>,
+
+    /// Override `lock` from the config file or environment.
+    #[arg(long, overrides_with("lock"))]
+    pub(crate) no_lock: bool,
+
+    /// Seconds to wait for a previous run to finish with `--lock=wait`.
+    /// Defaults to 60.
+    #[arg(long, value_name = "SECONDS")]
+    pub(crate) lock_wait: Option<u64>,
+
+    /// Send every line the command writes to this file descriptor (which
+    /// must be 3 or greater) to healthchecks' `/log` endpoint.
+    #[arg(long, value_name = "FD", value_parser = clap::value_parser!(i32).range(3..))]
+    pub(crate) log_fd: Option<i32>,
+
+    /// Minimum number of seconds between pings to `/log`; progress lines
+    /// received in the meantime are sent together. Defaults to 10.
+    #[arg(long, value_name = "SECONDS")]
+    pub(crate) log_interval: Option<u64>,
+
+    /// Send lines of the command's output that match this regular
+    /// expression to healthchecks' `/log` endpoint.
+    #[arg(long, value_name = "REGEX")]
+    pub(crate) log_pattern: Option<String>,
+
+    /// Exit with the code reported to healthchecks (i.e. `0` for any of
+    /// `--success-codes`) instead of mirroring the command's exit code.
+    #[arg(long, overrides_with("no_normalize_exit_code"))]
+    pub(crate) normalize_exit_code: bool,
+
+    /// Override `normalize_exit_code = true` from the config file or environment.
+    #[arg(long, overrides_with("normalize_exit_code"))]
+    pub(crate) no_normalize_exit_code: bool,
+
     /// Silence logging / warnings. Does not affect called command's output.
+    /// Overrides `verbose` from the config file or environment.
     #[arg(short, long, conflicts_with("verbose"))]
     pub quiet: bool,
 
+    /// Use the settings (including slug and command) from the
+    /// `[checks.<NAME>]` table of the config file.
+    #[arg(short, long, value_name = "NAME")]
+    pub(crate) profile: Option<String>,
+
+    /// Number of times to retry a failed ping to the healthchecks server.
+    /// Defaults to 3.
+    #[arg(long, value_name = "N")]
+    pub(crate) retries: Option<u32>,
+
+    /// Delay before the first retry of a failed ping, doubling with each
+    /// subsequent retry. Defaults to 1000.
+    #[arg(long, value_name = "MILLISECONDS")]
+    pub(crate) retry_backoff: Option<u64>,
+
+    /// Terminate the command and report a failure if it runs longer than
+    /// this many seconds, or 0 for no limit.
+    #[arg(long, value_name = "SECONDS")]
+    pub(crate) run_timeout: Option<u64>,
+
+    /// Run the command with `set -o pipefail` when using `--shell`, so that
+    /// a failure anywhere in a pipeline is reported. Requires a shell that
+    /// supports it, such as bash.
+    #[arg(long, overrides_with("no_pipefail"))]
+    pub(crate) pipefail: bool,
+
+    /// Override `pipefail = true` from the config file or environment.
+    #[arg(long, overrides_with("pipefail"))]
+    pub(crate) no_pipefail: bool,
+
+    /// Join the command's arguments into a single script and run it with
+    /// `--shell-path -c`, allowing pipes, redirects, etc.
+    #[arg(long, overrides_with("no_shell"))]
+    pub(crate) shell: bool,
+
+    /// Override `shell = true` from the config file or environment.
+    #[arg(long, overrides_with("shell"))]
+    pub(crate) no_shell: bool,
+
+    /// Shell to use with `--shell`. Defaults to `/bin/sh`.
+    #[arg(long, value_name = "PATH")]
+    pub(crate) shell_path: Option<PathBuf>,
+
+    /// Don't mask the ping key and check UUIDs in logs, error messages, and
+    /// `--dry-run` output. Useful for debugging.
+    #[arg(long, overrides_with("no_show_secrets"))]
+    pub(crate) show_secrets: bool,
+
+    /// Override `show_secrets = true` from the config file or environment.
+    #[arg(long, overrides_with("show_secrets"))]
+    pub(crate) no_show_secrets: bool,
+
+    /// Exit code to report to healthchecks if the command cannot be started
+    /// (e.g. it does not exist). By default this is reported to `/fail`.
+    #[arg(long, value_name = "CODE")]
+    pub(crate) spawn_failure_code: Option<u8>,
+
+    /// Fail instead of warning when the config file or `--url-file` is
+    /// readable by group or others or owned by another user.
+    #[arg(long, overrides_with("no_strict_permissions"))]
+    pub(crate) strict_permissions: bool,
+
+    /// Override `strict_permissions = true` from the config file or environment.
+    #[arg(long, overrides_with("strict_permissions"))]
+    pub(crate) no_strict_permissions: bool,
+
     /// Set healthchecks slug for this call.
-    #[arg(short, long, value_name = "NAME", value_parser=NonEmptyStringValueParser::new())]
-    pub(crate) slug: String,
+    #[arg(short, long, value_name = "NAME", required_unless_present_any(["profile", "uuid"]), value_parser=NonEmptyStringValueParser::new())]
+    pub(crate) slug: Option<String>,
+
+    /// Comma-separated non-zero exit codes to report to healthchecks as
+    /// success.
+    #[arg(
+        long,
+        value_name = "CODES",
+        value_delimiter = ',',
+        overrides_with("no_success_codes")
+    )]
+    pub(crate) success_codes: Vec<i32>,
+
+    /// Override `success_codes` from the config file or environment.
+    #[arg(long, overrides_with("success_codes"))]
+    pub(crate) no_success_codes: bool,
 
     /// Disable calling `/start` and only ping healthchecks if the test was successful.
-    #[arg(long)]
+    #[arg(long, overrides_with("no_success_only"))]
     pub(crate) success_only: bool,
 
+    /// Override `success_only = true` from the config file or environment.
+    #[arg(long, overrides_with("success_only"))]
+    pub(crate) no_success_only: bool,
+
     /// Set timeout for requests to healthchecks server.
     #[arg(short, long)]
     pub(crate) timeout: Option<u64>,
 
+    /// Which part of the output to keep when it exceeds `--body-limit`.
+    /// Defaults to `head-tail`.
+    #[arg(long, value_enum)]
+    pub(crate) truncate: Option<Truncation>,
+
     /// Specify the URL of the healthchecks server for this call.
     #[arg(short, long)]
     pub(crate) url: Option<Url>,
 
+    /// Run this shell command and use its output as the URL, e.g. `pass
+    /// show hc/url`.
+    #[arg(long, value_name = "COMMAND", conflicts_with_all(["url", "url_file"]))]
+    pub(crate) url_command: Option<String>,
+
+    /// Read the URL from this file, which must not be readable by all users.
+    #[arg(long, value_name = "PATH", conflicts_with("url"))]
+    pub(crate) url_file: Option<PathBuf>,
+
+    /// Ping the check with this UUID instead of using a slug. The URL should
+    /// not include a ping key in this case, e.g. `https://hc-ping.com/`.
+    #[arg(long, conflicts_with("slug"))]
+    pub(crate) uuid: Option<Uuid>,
+
     /// Increase logging verbosity. May be repeated. Defaults to `Level::WARN`.
+    /// Overrides `quiet` from the config file or environment.
     #[arg(short, long, action = clap::ArgAction::Count)]
     pub verbose: u8,
+
+    /// Options that were given on the command line
+    #[arg(skip)]
+    pub(crate) explicit: HashSet<String>,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+enum SubCommand {
+    /// Run the checks listed in a manifest concurrently. Options given
+    /// before `batch` apply to every check, overriding the manifest.
+    Batch {
+        /// Config file with a `[[checks]]` array of tables, each holding the
+        /// slug, command, and any other settings for a check
+        manifest: PathBuf,
+
+        /// Maximum number of checks to run at once. Defaults to
+        /// `concurrency` from the manifest, or 4.
+        #[arg(short = 'j', long, value_name = "N")]
+        concurrency: Option<usize>,
+    },
+
+    /// Check, show, or create config files
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+
+    /// Send pings that were spooled because healthchecks couldn't be reached.
+    /// This is also attempted at the start of every run.
+    Flush,
+
+    /// Show past runs recorded in the local history, oldest first
+    History {
+        /// Only show runs of the check with this slug (or UUID)
+        #[arg(short, long, value_name = "NAME")]
+        slug: Option<String>,
+
+        /// Only show runs that failed or whose end ping could not be sent
+        #[arg(long)]
+        failed: bool,
+
+        /// Print the runs (including their output) as JSON
+        #[arg(long)]
+        json: bool,
+
+        /// Only show the most recent runs
+        #[arg(short = 'n', long, value_name = "N")]
+        limit: Option<usize>,
+    },
+}
+
+/// Where each setting came from, for `--dry-run`
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Provenance {
+    /// The config files that were read, lowest precedence first
+    pub(crate) files: Vec<PathBuf>,
+    /// The name of each source and the keys it set, lowest precedence first
+    layers: Vec<(String, HashSet<String>)>,
+}
+
+impl Provenance {
+    fn push(&mut self, name: impl Into<String>, keys: HashSet<String>) {
+        self.layers.push((name.into(), keys));
+    }
+
+    /// The source that `key` was taken from
+    pub(crate) fn source(&self, key: &str) -> &str {
+        self.layers
+            .iter()
+            .rev()
+            .find(|(_, keys)| keys.contains(key))
+            .map_or("default", |(name, _)| name)
+    }
+
+    /// The position of the source that `key` was taken from, if any, with
+    /// later sources taking precedence
+    fn rank(&self, key: &str) -> Option<usize> {
+        self.layers.iter().rposition(|(_, keys)| keys.contains(key))
+    }
+
+    /// Attribute `key` to the sources of `from`, for a setting that is
+    /// derived from another
+    fn derive(&mut self, key: &str, from: &str) {
+        for (_, keys) in &mut self.layers {
+            if keys.contains(from) {
+                keys.insert(key.into());
+            } else {
+                keys.remove(key);
+            }
+        }
+    }
+}
+
+/// Resolve the base URL from whichever of `url`, `url_file`, and
+/// `url_command` was set by the source with the highest precedence.
+fn resolve_url(
+    url: Option<Url>,
+    url_file: Option<PathBuf>,
+    url_command: Option<String>,
+    provenance: &mut Provenance,
+    strict_permissions: bool,
+    resolved: &mut Resolved,
+) -> Result<Url> {
+    let mut given: Vec<_> = [
+        url.is_some().then_some("url"),
+        url_file.is_some().then_some("url_file"),
+        url_command.is_some().then_some("url_command"),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    given.sort_by_key(|key| provenance.rank(key));
+    let key = match given[..] {
+        [] => return Err(Error::Config("Base URL not found".into())),
+        [.., a, b] if provenance.rank(a) == provenance.rank(b) => {
+            return Err(Error::Config(format!(
+                "{a} and {b} are mutually exclusive (both set by {})",
+                provenance.source(b)
+            )));
+        }
+        [.., key] => key,
+    };
+    provenance.derive("url", key);
+    match (key, url, url_file, url_command) {
+        ("url", Some(url), ..) => Ok(url),
+        ("url_file", _, Some(path), _) => {
+            secret::read_url_file(&path, strict_permissions, resolved)
+        }
+        ("url_command", .., Some(command)) => {
+            secret::run_url_command(&command, resolved)
+        }
+        _ => Err(Error::Unknown),
+    }
+}
+
+/// Directory of the system-wide config file, e.g. maintained by ops on a
+/// shared server
+const SYSTEM_CONFIG_DIR: &str = "/etc/hc-runner";
+
+/// Extensions of the supported config file formats
+const CONFIG_EXTENSIONS: [&str; 4] = ["toml", "yaml", "yml", "json"];
+
+/// Find the config file in `dir`, which may be in any supported format but
+/// must be unique.
+fn find_config_file(dir: &Path) -> Result<Option<PathBuf>> {
+    let found: Vec<_> = CONFIG_EXTENSIONS
+        .iter()
+        .map(|ext| dir.join(format!("config.{ext}")))
+        .filter(|path| path.exists())
+        .collect();
+    match &found[..] {
+        [] => Ok(None),
+        [path] => Ok(Some(path.clone())),
+        _ => Err(Error::Config(format!(
+            "found multiple config files, remove all but one: {}",
+            found
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))),
+    }
+}
+
+/// The source for the config file at `path`, or `None` if it isn't a
+/// regular file and so has nothing to read (e.g. `--config /dev/null`)
+fn config_file(
+    path: &Path,
+) -> Result<Option<File<FileSourceFile, FileFormat>>> {
+    if !path.is_file() {
+        return Ok(None);
+    }
+    // config-rs would report any other file as not found
+    if !path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| CONFIG_EXTENSIONS.contains(&ext))
+    {
+        return Err(Error::Config(format!(
+            "unsupported config file extension for {}, expected one of: {}",
+            path.display(),
+            CONFIG_EXTENSIONS.join(", ")
+        )));
+    }
+    Ok(Some(File::from(path)))
+}
+
+/// A place that is searched for a config file
+pub(crate) struct Location {
+    /// Where the file is looked for, e.g.
+    /// `/etc/hc-runner/config.{toml,yaml,yml,json}`
+    searched: PathBuf,
+    /// The file that was found, if any
+    found: Option<PathBuf>,
+    /// Whether the file belongs to the user, and so should be private
+    private: bool,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.found {
+            Some(found) => write!(f, "{}", found.display()),
+            None => write!(f, "{} (not found)", self.searched.display()),
+        }
+    }
+}
+
+/// The places searched for config files given `cli`, lowest precedence
+/// first, ending with `--config` if given
+fn locations(cli: &Cli) -> Result<Vec<Location>> {
+    let mut locations = Vec::new();
+    for (dir, private) in config_dirs() {
+        locations.push(Location {
+            searched: dir
+                .join(format!("config.{{{}}}", CONFIG_EXTENSIONS.join(","))),
+            found: find_config_file(&dir)?,
+            private,
+        });
+    }
+    if let Some(path) = &cli.config {
+        locations.push(Location {
+            searched: path.clone(),
+            found: path.exists().then(|| path.clone()),
+            private: true,
+        });
+    }
+    Ok(locations)
+}
+
+/// The directories searched for config files, lowest precedence first: the
+/// system directory, `$XDG_CONFIG_DIRS`, and the user's config directory.
+/// Whether each belongs to the user is also returned, since only those are
+/// expected to be private.
+fn config_dirs() -> Vec<(PathBuf, bool)> {
+    let mut dirs = vec![(PathBuf::from(SYSTEM_CONFIG_DIR), false)];
+
+    let xdg = env::var_os("XDG_CONFIG_DIRS")
+        .filter(|dirs| !dirs.is_empty())
+        .unwrap_or_else(|| "/etc/xdg".into());
+    // The first of `$XDG_CONFIG_DIRS` is the most important
+    let xdg: Vec<_> = env::split_paths(&xdg)
+        .filter(|dir| dir.is_absolute())
+        .map(|dir| (dir.join("hc-runner"), false))
+        .collect();
+    dirs.extend(xdg.into_iter().rev());
+
+    if let Ok(pd) = project_dirs() {
+        dirs.push((pd.config_dir().to_path_buf(), true));
+    }
+    dirs
 }
 
 /// Settings that are configurable via config file or environment variables
 /// Order of priority (higher numbers override lower)
-/// 1. Config file
+/// 1. Config files (system, `$XDG_CONFIG_DIRS`, user, then `--config`)
 /// 2. Environment variables
-/// 3. CLI flags
-#[derive(Debug, Deserialize)]
+/// 3. Profile (`[checks.<name>]` table in the config file)
+/// 4. CLI flags
+#[derive(Debug, Deserialize, Serialize)]
 struct Settings {
+    body_limit: Option<usize>,
+    body_format: Option<BodyFormat>,
+    body_source: Option<BodySource>,
+    command: Option<Vec<String>>,
+    history_limit: Option<usize>,
+    kill_after: Option<u64>,
+    lock: Option<LockPolicy>,
+    lock_wait: Option<u64>,
+    log_fd: Option<i32>,
+    log_interval: Option<u64>,
+    log_pattern: Option<String>,
+    normalize_exit_code: Option<bool>,
+    pipefail: Option<bool>,
+    quiet: Option<bool>,
+    retries: Option<u32>,
+    retry_backoff: Option<u64>,
+    run_timeout: Option<u64>,
+    shell: Option<bool>,
+    shell_path: Option<PathBuf>,
+    show_secrets: Option<bool>,
+    slug: Option<String>,
+    spawn_failure_code: Option<u8>,
+    strict_permissions: Option<bool>,
+    success_codes: Option<Vec<i32>>,
+    success_only: Option<bool>,
     url: Option<Url>,
+    url_command: Option<String>,
+    url_file: Option<PathBuf>,
     timeout: Option<u64>,
+    truncate: Option<Truncation>,
+    uuid: Option<Uuid>,
+    verbose: Option<u8>,
+
+    #[serde(skip)]
+    provenance: Provenance,
+}
+
+impl Settings {
+    /// Merge settings from the config files, environment, and profile
+    /// selected by `cli`
+    fn load(cli: &Cli) -> Result<Self> {
+        let mut builder = config_rs::Config::builder();
+        let mut provenance = Provenance::default();
+
+        // tracing not configured until after this method returns, so this
+        // is a non-pretty workaround to help users find where config files
+        // should be placed
+        let locations = locations(cli)?;
+        if cli.verbose >= 2 {
+            writeln!(
+                io::stderr(),
+                "searching for config files, later overriding earlier:"
+            )?;
+            for location in &locations {
+                writeln!(io::stderr(), "  {location}")?;
+            }
+        }
+
+        for conf_file in locations.iter().filter_map(|l| l.found.as_ref()) {
+            let Some(file) = config_file(conf_file)? else {
+                continue;
+            };
+            provenance.push(
+                format!("config file `{}`", conf_file.display()),
+                file.collect()?.into_keys().collect(),
+            );
+            provenance.files.push(conf_file.clone());
+            builder = builder.add_source(file);
+        }
+        let env = Environment::with_prefix("HC_RUNNER")
+            .try_parsing(true)
+            .list_separator(",")
+            .with_list_parse_key("success_codes");
+        provenance.push("environment", env.collect()?.into_keys().collect());
+        builder = builder.add_source(env);
+
+        // A profile's settings take precedence over the rest of the config
+        // file and the environment, but not over CLI flags
+        if let Some(profile) = &cli.profile {
+            let key = format!("checks.{profile}");
+            let table =
+                builder.build_cloned()?.get_table(&key).map_err(|_| {
+                    Error::Config(format!("profile `{profile}` not found"))
+                })?;
+            provenance.push(
+                format!("profile `{profile}`"),
+                table.keys().cloned().collect(),
+            );
+            for (key, value) in table {
+                builder = builder.set_override(key, value)?;
+            }
+        }
+
+        let mut settings: Self = builder.build()?.try_deserialize()?;
+        // System-wide config files are meant to be shared
+        for file in locations
+            .iter()
+            .filter(|location| location.private)
+            .filter_map(|location| location.found.as_ref())
+        {
+            secret::check_permissions(
+                file,
+                "config file",
+                settings.strict_permissions(cli),
+            )?;
+        }
+        if flag(cli.show_secrets, cli.no_show_secrets)
+            .or(settings.show_secrets)
+            .unwrap_or_default()
+        {
+            redact::show();
+        }
+        settings.provenance = provenance;
+        Ok(settings)
+    }
+
+    /// These settings with those of a batch manifest `entry` taking
+    /// precedence, without loading the config files again
+    fn overlay(&self, entry: Map<String, Value>) -> Result<Self> {
+        let mut builder = config_rs::Config::builder()
+            .add_source(config_rs::Config::try_from(self)?);
+        let mut provenance = self.provenance.clone();
+        provenance.push("manifest", entry.keys().cloned().collect());
+        for (key, value) in entry {
+            builder = builder.set_override(key, value)?;
+        }
+
+        let mut settings: Self = builder.build()?.try_deserialize()?;
+        settings.provenance = provenance;
+        Ok(settings)
+    }
+}
+
+impl Settings {
+    fn strict_permissions(&self, cli: &Cli) -> bool {
+        flag(cli.strict_permissions, cli.no_strict_permissions)
+            .or(self.strict_permissions)
+            .unwrap_or_default()
+    }
+
+    /// `--quiet` and `--verbose` each override both `quiet` and `verbose`
+    /// from the config file or environment
+    fn verbosity(&self, cli: &Cli) -> Level {
+        let n = if cli.quiet || cli.verbose > 0 {
+            if cli.quiet { 0 } else { cli.verbose }
+        } else if self.quiet.unwrap_or_default() {
+            0
+        } else {
+            self.verbose.unwrap_or_default()
+        };
+        parse_verbosity(n)
+    }
+}
+
+/// Resolve a pair of flags like `--shell` and `--no-shell`, at most one of
+/// which is set, to the value given on the command line if any.
+fn flag(yes: bool, no: bool) -> Option<bool> {
+    match (yes, no) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+impl Cli {
+    /// Parse `args`, recording which options were given explicitly
+    fn try_parse_args<I, T>(args: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let matches = Self::command().try_get_matches_from(args)?;
+        let mut cli = Self::from_arg_matches(&matches)?;
+        cli.explicit = matches
+            .ids()
+            .filter(|id| {
+                matches.value_source(id.as_str())
+                    == Some(ValueSource::CommandLine)
+            })
+            // `--no-shell` is recorded as setting `shell`, etc.
+            .map(|id| id.as_str().strip_prefix("no_").unwrap_or(id.as_str()))
+            .map(ToString::to_string)
+            .collect();
+        Ok(cli)
+    }
 }
 
 fn parse_verbosity(n: u8) -> Level {
@@ -71,78 +712,421 @@ fn parse_verbosity(n: u8) -> Level {
     }
 }
 
+/// How the check is identified in ping URLs
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Check {
+    /// Slug relative to a URL containing the project's ping key
+    Slug(String),
+    /// UUID of the check, which doesn't require a ping key
+    Uuid(Uuid),
+}
+
+impl Check {
+    fn from_parts(
+        slug: Option<String>,
+        uuid: Option<Uuid>,
+    ) -> Result<Option<Self>> {
+        match (slug, uuid) {
+            (Some(_), Some(_)) => Err(Error::Config(
+                "slug and uuid are mutually exclusive".into(),
+            )),
+            (Some(slug), None) => Ok(Some(Self::Slug(slug))),
+            (None, Some(uuid)) => Ok(Some(Self::Uuid(uuid))),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Slug(slug) => write!(f, "{slug}"),
+            Self::Uuid(uuid) => write!(f, "{uuid}"),
+        }
+    }
+}
+
+/// Shell used to run the command with `--shell`
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Shell {
+    pub(crate) path: PathBuf,
+    pub(crate) pipefail: bool,
+}
+
 #[derive(Debug)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct Config {
+    pub(crate) body_limit: usize,
+    pub(crate) body_format: BodyFormat,
+    pub(crate) body_source: BodySource,
+    pub(crate) check: Check,
     pub(crate) command: Vec<String>,
-    pub(crate) slug: String,
+    pub(crate) dry_run: bool,
+    pub(crate) history_limit: usize,
+    pub(crate) kill_after: u64,
+    pub(crate) lock: Option<LockPolicy>,
+    pub(crate) lock_wait: u64,
+    pub(crate) log_fd: Option<i32>,
+    pub(crate) log_interval: u64,
+    pub(crate) log_pattern: Option<Regex>,
+    pub(crate) normalize_exit_code: bool,
+    pub(crate) provenance: Provenance,
+    pub(crate) retries: u32,
+    pub(crate) retry_backoff: u64,
+    pub(crate) run_timeout: Option<u64>,
+    pub(crate) shell: Option<Shell>,
+    pub(crate) spawn_failure_code: Option<u8>,
+    pub(crate) strict_permissions: bool,
+    pub(crate) success_codes: Vec<i32>,
     pub(crate) success_only: bool,
     pub(crate) timeout: u64,
+    pub(crate) truncate: Truncation,
     pub(crate) url: Url,
     pub verbosity: Level,
 }
 
-impl Config {
+/// What hc-runner has been asked to do
+#[derive(Debug)]
+pub enum Action {
+    /// Run a single command
+    Run(Box<Config>),
+    /// Run the checks from a manifest
+    Batch(Batch),
+    /// Check, show, or create config files
+    Config(Inspect),
+    /// Send spooled pings
+    Flush(Flush),
+    /// Show the local run history
+    History(Query),
+}
+
+impl Action {
     #[tracing::instrument]
     /// # Errors
     /// Returns an error if CLI arguments can't be parsed
     pub fn resolve() -> Result<Self> {
-        let cli = Cli::try_parse()?;
+        let cli = Cli::try_parse_args(env::args_os())?;
         Self::resolve_with(cli)
     }
 
     fn resolve_with(cli: Cli) -> Result<Self> {
-        let mut builder = config_rs::Config::builder();
+        match &cli.subcommand {
+            None => {
+                let config = Config::resolve_with(cli)?;
+                redact::register(&config.url, &config.check);
+                Ok(Self::Run(Box::new(config)))
+            }
+            Some(SubCommand::Batch {
+                manifest,
+                concurrency,
+            }) => {
+                let batch = resolve_batch(&cli, manifest, *concurrency)?;
+                for config in &batch.configs {
+                    redact::register(&config.url, &config.check);
+                }
+                Ok(Self::Batch(batch))
+            }
+            // Settings aren't loaded here, since `config check` should
+            // report any problems with them
+            Some(SubCommand::Config { command }) => {
+                Ok(Self::Config(Inspect {
+                    command: command.clone(),
+                    verbosity: parse_verbosity(if cli.quiet {
+                        0
+                    } else {
+                        cli.verbose
+                    }),
+                    cli: Box::new(cli.clone()),
+                }))
+            }
+            Some(SubCommand::Flush) => {
+                let settings = Settings::load(&cli)?;
+                // Spooled URLs are most likely for the configured check
+                if let (Some(url), Some(check)) = (
+                    cli.url.as_ref().or(settings.url.as_ref()),
+                    Check::from_parts(
+                        cli.slug.clone().or(settings.slug.clone()),
+                        cli.uuid.or(settings.uuid),
+                    )?,
+                ) {
+                    redact::register(url, &check);
+                }
+                Ok(Self::Flush(Flush {
+                    timeout: cli.timeout.or(settings.timeout).unwrap_or(10),
+                    retries: cli.retries.or(settings.retries).unwrap_or(3),
+                    retry_backoff: cli
+                        .retry_backoff
+                        .or(settings.retry_backoff)
+                        .unwrap_or(1000),
+                    verbosity: settings.verbosity(&cli),
+                }))
+            }
+            Some(SubCommand::History {
+                slug,
+                failed,
+                json,
+                limit,
+            }) => {
+                let settings = Settings::load(&cli)?;
+                Ok(Self::History(Query {
+                    check: slug.clone(),
+                    failed: *failed,
+                    json: *json,
+                    limit: *limit,
+                    verbosity: settings.verbosity(&cli),
+                }))
+            }
+        }
+    }
 
-        let conf_file = cli.config.or_else(|| {
-            ProjectDirs::from("com", "n8henrie", "hc-runner")
-                .map(|pd| pd.config_dir().join("config.toml"))
-        });
+    #[must_use]
+    pub fn verbosity(&self) -> Level {
+        match self {
+            Self::Run(config) => config.verbosity,
+            Self::Batch(batch) => batch.verbosity,
+            Self::Config(inspect) => inspect.verbosity,
+            Self::Flush(flush) => flush.verbosity,
+            Self::History(query) => query.verbosity,
+        }
+    }
+}
 
-        if let Some(conf_file) = conf_file {
-            // tracing not configured until after this method returns, so
-            // this is a non-pretty workaround to help users find where the
-            // config file should be placed
-            if cli.verbose >= 2 {
-                writeln!(
-                    io::stderr(),
-                    "searching for config file at {}",
-                    conf_file.display(),
-                )?;
-            }
-            builder =
-                builder.add_source(File::from(conf_file).required(false));
+/// Resolve a `Config` for each entry in the manifest, which take precedence
+/// over the config file and environment but not CLI flags.
+fn resolve_batch(
+    cli: &Cli,
+    manifest: &Path,
+    concurrency: Option<usize>,
+) -> Result<Batch> {
+    if cli.slug.is_some()
+        || cli.uuid.is_some()
+        || cli.profile.is_some()
+        || !cli.command.is_empty()
+    {
+        return Err(Error::Config(
+            "slug, uuid, profile, and command must be given in the batch \
+            manifest"
+                .into(),
+        ));
+    }
+
+    let manifest = config_rs::Config::builder()
+        .add_source(File::from(manifest))
+        .build()?;
+    let concurrency = match concurrency {
+        Some(n) => n,
+        None => manifest.get::<Option<usize>>("concurrency")?.unwrap_or(4),
+    };
+    let settings = Settings::load(cli)?;
+    let mut resolved = Resolved::new();
+    let configs = manifest
+        .get_array("checks")?
+        .into_iter()
+        .map(|entry| {
+            let settings = settings.overlay(entry.into_table()?)?;
+            Config::from_settings(cli.clone(), settings, &mut resolved)
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(Batch {
+        configs,
+        concurrency,
+        verbosity: settings.verbosity(cli),
+    })
+}
+
+impl Config {
+    fn resolve_with(cli: Cli) -> Result<Self> {
+        let settings = Settings::load(&cli)?;
+        Self::from_settings(cli, settings, &mut Resolved::new())
+    }
+
+    /// Combine CLI flags with `settings`, with flags taking precedence
+    // Mostly one line per setting
+    #[allow(clippy::too_many_lines)]
+    fn from_settings(
+        cli: Cli,
+        settings: Settings,
+        resolved: &mut Resolved,
+    ) -> Result<Self> {
+        let verbosity = settings.verbosity(&cli);
+        macro_rules! merge {
+            ($field:ident) => {
+                cli.$field.or(settings.$field)
+            };
+        }
+        macro_rules! merge_flag {
+            ($field:ident, $no:ident) => {
+                flag(cli.$field, cli.$no)
+                    .or(settings.$field)
+                    .unwrap_or_default()
+            };
         }
-        let settings: Settings = builder
-            .add_source(Environment::with_prefix("HC_RUNNER"))
-            .build()?
-            .try_deserialize()?;
 
-        let url = cli
-            .url
-            .or(settings.url)
-            .ok_or_else(|| Error::Config("Base URL not found".into()))?;
+        let strict_permissions = settings.strict_permissions(&cli);
+        let mut provenance = settings.provenance;
+        provenance.push("command line", cli.explicit.clone());
+        let url = resolve_url(
+            merge!(url),
+            merge!(url_file),
+            merge!(url_command),
+            &mut provenance,
+            strict_permissions,
+            resolved,
+        )?;
 
-        let timeout: u64 = cli.timeout.or(settings.timeout).unwrap_or(10);
+        let timeout: u64 = merge!(timeout).unwrap_or(10);
+        // 0 overrides a limit from the config file or environment
+        let run_timeout = merge!(run_timeout).filter(|&secs| secs != 0);
+        let kill_after = merge!(kill_after).unwrap_or(10);
+        let history_limit = merge!(history_limit).unwrap_or(1000);
+        let body_limit = merge!(body_limit).unwrap_or(100_000);
+        let body_format = merge!(body_format).unwrap_or_default();
+        let body_source = merge!(body_source).unwrap_or_default();
+        let truncate = merge!(truncate).unwrap_or_default();
+        let lock = if cli.no_lock { None } else { merge!(lock) };
+        let lock_wait = merge!(lock_wait).unwrap_or(60);
+        let log_fd = merge!(log_fd);
+        if log_fd.is_some_and(|fd| fd < 3) {
+            return Err(Error::Config("log_fd must be 3 or greater".into()));
+        }
+        let log_interval = merge!(log_interval).unwrap_or(10);
+        let log_pattern = merge!(log_pattern)
+            .map(|pattern| Regex::new(&pattern))
+            .transpose()?;
+        let shell_path =
+            merge!(shell_path).unwrap_or_else(|| "/bin/sh".into());
+        let spawn_failure_code = merge!(spawn_failure_code);
+        let retries = merge!(retries).unwrap_or(3);
+        let retry_backoff = merge!(retry_backoff).unwrap_or(1000);
+        let normalize_exit_code =
+            merge_flag!(normalize_exit_code, no_normalize_exit_code);
+        let success_only = merge_flag!(success_only, no_success_only);
+        let shell = merge_flag!(shell, no_shell).then(|| Shell {
+            path: shell_path,
+            pipefail: merge_flag!(pipefail, no_pipefail),
+        });
 
-        let verbosity =
-            parse_verbosity(if cli.quiet { 0 } else { cli.verbose });
         let Cli {
             command,
+            dry_run,
             slug,
-            success_only,
+            success_codes,
+            no_success_codes,
+            uuid,
             ..
         } = cli;
 
+        let command = if command.is_empty() {
+            settings.command.unwrap_or_default()
+        } else {
+            command
+        };
+        if command.is_empty() {
+            return Err(Error::Config("command was empty".into()));
+        }
+        let check = match Check::from_parts(slug, uuid)? {
+            Some(check) => check,
+            None => Check::from_parts(settings.slug, settings.uuid)?
+                .ok_or_else(|| {
+                    Error::Config("slug or uuid not found".into())
+                })?,
+        };
+        let success_codes = if no_success_codes || !success_codes.is_empty() {
+            success_codes
+        } else {
+            settings.success_codes.unwrap_or_default()
+        };
+
         Ok(Self {
+            body_limit,
+            body_format,
+            body_source,
+            check,
             command,
-            slug,
+            dry_run,
+            history_limit,
+            kill_after,
+            lock,
+            lock_wait,
+            log_fd,
+            log_interval,
+            log_pattern,
+            normalize_exit_code,
+            provenance,
+            retries,
+            retry_backoff,
+            run_timeout,
+            shell,
+            spawn_failure_code,
+            strict_permissions,
+            success_codes,
             success_only,
             timeout,
+            truncate,
             url,
             verbosity,
         })
     }
 }
 
+impl Config {
+    /// The name and value of each setting, for `--dry-run`
+    pub(crate) fn settings(&self) -> Vec<(&'static str, String)> {
+        fn opt(value: Option<impl ToString>) -> String {
+            value.map_or_else(|| "-".into(), |value| value.to_string())
+        }
+        fn name(value: &impl ValueEnum) -> String {
+            opt(value
+                .to_possible_value()
+                .map(|value| value.get_name().to_owned()))
+        }
+        fn list<T: ToString>(values: &[T]) -> String {
+            if values.is_empty() {
+                return "-".into();
+            }
+            values
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(",")
+        }
+
+        let check = match &self.check {
+            Check::Slug(slug) => ("slug", slug.clone()),
+            Check::Uuid(uuid) => ("uuid", uuid.to_string()),
+        };
+        let shell = self.shell.as_ref();
+        vec![
+            ("body_format", name(&self.body_format)),
+            ("body_limit", self.body_limit.to_string()),
+            ("body_source", name(&self.body_source)),
+            ("command", format!("{:?}", self.command)),
+            ("history_limit", self.history_limit.to_string()),
+            ("kill_after", self.kill_after.to_string()),
+            ("lock", opt(self.lock.as_ref().map(name))),
+            ("lock_wait", self.lock_wait.to_string()),
+            ("log_fd", opt(self.log_fd)),
+            ("log_interval", self.log_interval.to_string()),
+            ("log_pattern", opt(self.log_pattern.as_ref())),
+            ("normalize_exit_code", self.normalize_exit_code.to_string()),
+            ("pipefail", opt(shell.map(|shell| shell.pipefail))),
+            ("retries", self.retries.to_string()),
+            ("retry_backoff", self.retry_backoff.to_string()),
+            ("run_timeout", opt(self.run_timeout)),
+            ("shell", shell.is_some().to_string()),
+            ("shell_path", opt(shell.map(|shell| shell.path.display()))),
+            check,
+            ("spawn_failure_code", opt(self.spawn_failure_code)),
+            ("strict_permissions", self.strict_permissions.to_string()),
+            ("success_codes", list(&self.success_codes)),
+            ("success_only", self.success_only.to_string()),
+            ("timeout", self.timeout.to_string()),
+            ("truncate", name(&self.truncate)),
+            ("url", self.url.to_string()),
+        ]
+    }
+}
+
 #[cfg(test)]
 mod tests;
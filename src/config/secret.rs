@@ -0,0 +1,130 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{LazyLock, Mutex};
+
+use reqwest::Url;
+
+use crate::{Error, Result};
+
+/// Files whose permissions have already been checked, so that each is only
+/// warned about once (e.g. for every check in a batch)
+static CHECKED: LazyLock<Mutex<HashSet<PathBuf>>> =
+    LazyLock::new(Mutex::default);
+
+/// URLs that have already been read from a file or command, keyed by where
+/// they came from, so that e.g. `pass` only prompts once for a whole batch
+pub(super) type Resolved = HashMap<String, Url>;
+
+/// Like ssh does for private keys, complain about a file containing secrets
+/// (`what`) that is readable by group or others or owned by another user,
+/// failing if `strict`.
+pub(super) fn check_permissions(
+    path: &Path,
+    what: &str,
+    strict: bool,
+) -> Result<()> {
+    let metadata = fs::metadata(path)?;
+    // e.g. `--config=/dev/null`
+    if !metadata.is_file() {
+        return Ok(());
+    }
+    let owner = metadata.uid();
+    // SAFETY: `geteuid` is always successful
+    let euid = unsafe { libc::geteuid() };
+    let problem = if owner != euid && owner != 0 {
+        format!("is owned by another user (uid {owner})")
+    } else if metadata.permissions().mode() & 0o044 != 0 {
+        "is readable by group or others, restrict it with `chmod 0600`".into()
+    } else {
+        return Ok(());
+    };
+    let msg = format!("{what} `{}` {problem}", path.display());
+    if strict {
+        return Err(Error::Config(msg));
+    }
+
+    let mut checked = CHECKED
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    if checked.insert(path.to_path_buf()) {
+        // tracing isn't configured until the config has been resolved
+        writeln!(io::stderr(), "hc-runner: warning: {msg}")?;
+    }
+    Ok(())
+}
+
+/// Parse a URL read from a file or command, ignoring surrounding whitespace
+/// (e.g. a trailing newline).
+fn parse(text: &str, from: &str) -> Result<Url> {
+    Url::parse(text.trim())
+        .map_err(|e| Error::Config(format!("invalid URL from {from}: {e}")))
+}
+
+/// The URL from `from`, calling `read` unless it is already `resolved`.
+fn cached(
+    resolved: &mut Resolved,
+    from: &str,
+    read: impl FnOnce() -> Result<Url>,
+) -> Result<Url> {
+    if let Some(url) = resolved.get(from) {
+        return Ok(url.clone());
+    }
+    let url = read()?;
+    resolved.insert(from.into(), url.clone());
+    Ok(url)
+}
+
+/// Read the base URL from `path`, which must not be readable by all users
+/// since the URL contains the ping key.
+pub(super) fn read_url_file(
+    path: &Path,
+    strict: bool,
+    resolved: &mut Resolved,
+) -> Result<Url> {
+    let from = format!("url_file `{}`", path.display());
+    cached(resolved, &from, || {
+        let metadata = fs::metadata(path).map_err(|e| {
+            Error::Config(format!("failed to read {from}: {e}"))
+        })?;
+        if metadata.permissions().mode() & 0o004 != 0 {
+            return Err(Error::Config(format!(
+                "{from} is readable by all users, restrict it with `chmod o-r`"
+            )));
+        }
+        check_permissions(path, "url_file", strict)?;
+        let text = fs::read_to_string(path).map_err(|e| {
+            Error::Config(format!("failed to read {from}: {e}"))
+        })?;
+        parse(&text, &from)
+    })
+}
+
+/// Run `command` with `/bin/sh -c` and use its output as the base URL, e.g.
+/// `pass show hc/url`.
+pub(super) fn run_url_command(
+    command: &str,
+    resolved: &mut Resolved,
+) -> Result<Url> {
+    let from = format!("url_command `{command}`");
+    cached(resolved, &from, || {
+        let output = Command::new("/bin/sh")
+            .args(["-c", command])
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .output()
+            .map_err(|e| {
+                Error::Config(format!("failed to run {from}: {e}"))
+            })?;
+        if !output.status.success() {
+            return Err(Error::Config(format!(
+                "{from} failed: {}",
+                output.status
+            )));
+        }
+        parse(&String::from_utf8_lossy(&output.stdout), &from)
+    })
+}
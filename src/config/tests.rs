@@ -44,7 +44,7 @@ fn test_config_parser() {
         "bar",
         "foo bar",
     ]);
-    assert_eq!(config.slug, "fake");
+    assert_eq!(config.slug.as_deref(), Some("fake"));
     assert_eq!(config.verbose, 2);
     assert_eq!(
         config.command,
@@ -55,7 +55,7 @@ fn test_config_parser() {
         Cli::parse_from(["", "--slug", "fake", "--", "cat"]),
         Cli::parse_from(["", "-s", "fake", "cat"]),
     ] {
-        assert_eq!(config.slug, "fake");
+        assert_eq!(config.slug.as_deref(), Some("fake"));
         assert!(!config.success_only);
         assert_eq!(config.command, vec!["cat"]);
     }
@@ -171,3 +171,623 @@ fn test_default_config() {
 
     drop(env_guard);
 }
+
+#[test]
+fn test_run_timeout_overrides() {
+    let env_guard = ENV_LOCK.lock().unwrap();
+    unsafe {
+        env::remove_var("HC_RUNNER_RUN_TIMEOUT");
+        env::remove_var("HC_RUNNER_KILL_AFTER");
+        env::set_var("HOME", "/dev/null");
+    }
+
+    let cli = Cli::parse_from([
+        "",
+        "--url=https://n8henrie.com",
+        "--slug=test",
+        "fake_command",
+    ]);
+    let config = Config::resolve_with(cli.clone()).unwrap();
+    assert_eq!(config.run_timeout, None);
+    assert_eq!(config.kill_after, 10);
+
+    let _tmp = temp_config("run_timeout = 60\nkill_after = 5");
+    let config = Config::resolve_with(cli).unwrap();
+    assert_eq!(config.run_timeout, Some(60));
+    assert_eq!(config.kill_after, 5);
+
+    let cli = Cli::parse_from([
+        "",
+        "--url=https://n8henrie.com",
+        "--slug=test",
+        "--run-timeout=30",
+        "fake_command",
+    ]);
+    let config = Config::resolve_with(cli).unwrap();
+    assert_eq!(config.run_timeout, Some(30));
+
+    // 0 disables the timeout from the config file
+    let cli = Cli::parse_from([
+        "",
+        "--url=https://n8henrie.com",
+        "--slug=test",
+        "--run-timeout=0",
+        "fake_command",
+    ]);
+    let config = Config::resolve_with(cli).unwrap();
+    assert_eq!(config.run_timeout, None);
+
+    drop(env_guard);
+}
+
+#[test]
+fn test_success_codes() {
+    let env_guard = ENV_LOCK.lock().unwrap();
+    unsafe {
+        env::remove_var("HC_RUNNER_SUCCESS_CODES");
+        env::set_var("HOME", "/dev/null");
+    }
+
+    let cli = Cli::parse_from([
+        "",
+        "--url=https://n8henrie.com",
+        "--slug=test",
+        "fake_command",
+    ]);
+    let config = Config::resolve_with(cli.clone()).unwrap();
+    assert!(config.success_codes.is_empty());
+    assert!(!config.normalize_exit_code);
+
+    unsafe {
+        env::set_var("HC_RUNNER_SUCCESS_CODES", "1,24");
+    }
+    let config = Config::resolve_with(cli).unwrap();
+    assert_eq!(config.success_codes, vec![1, 24]);
+
+    let cli = Cli::parse_from([
+        "",
+        "--url=https://n8henrie.com",
+        "--slug=test",
+        "--success-codes=3,4",
+        "--normalize-exit-code",
+        "fake_command",
+    ]);
+    let config = Config::resolve_with(cli).unwrap();
+    assert_eq!(config.success_codes, vec![3, 4]);
+    assert!(config.normalize_exit_code);
+
+    unsafe {
+        env::remove_var("HC_RUNNER_SUCCESS_CODES");
+    }
+    drop(env_guard);
+}
+
+#[test]
+fn test_profile() {
+    let env_guard = ENV_LOCK.lock().unwrap();
+    unsafe {
+        env::remove_var("HC_RUNNER_TIMEOUT");
+        env::remove_var("HC_RUNNER_URL");
+    }
+    let _tmp = temp_config(
+        r#"
+        url = "https://n8henrie.com"
+        timeout = 20
+
+        [checks.backup]
+        slug = "nightly-backup"
+        command = ["restic", "backup", "/"]
+        success_only = true
+        timeout = 30
+        success_codes = [3]
+        "#,
+    );
+
+    let cli = Cli::try_parse_from(["", "--profile=backup"]).unwrap();
+    let config = Config::resolve_with(cli).unwrap();
+    assert_eq!(config.check, Check::Slug("nightly-backup".into()));
+    assert_eq!(config.command, vec!["restic", "backup", "/"]);
+    assert!(config.success_only);
+    assert_eq!(config.timeout, 30);
+    assert_eq!(config.success_codes, vec![3]);
+    assert_eq!(config.url.as_str(), "https://n8henrie.com/");
+
+    // profile overrides environment
+    unsafe {
+        env::set_var("HC_RUNNER_TIMEOUT", "40");
+    }
+    let cli = Cli::try_parse_from(["", "--profile=backup"]).unwrap();
+    let config = Config::resolve_with(cli).unwrap();
+    assert_eq!(config.timeout, 30);
+    unsafe {
+        env::remove_var("HC_RUNNER_TIMEOUT");
+    }
+
+    // CLI overrides profile
+    let cli = Cli::try_parse_from([
+        "",
+        "--profile=backup",
+        "--slug=other",
+        "--timeout=50",
+        "--",
+        "echo",
+        "foo",
+    ])
+    .unwrap();
+    let config = Config::resolve_with(cli).unwrap();
+    assert_eq!(config.check, Check::Slug("other".into()));
+    assert_eq!(config.timeout, 50);
+    assert_eq!(config.command, vec!["echo", "foo"]);
+
+    let cli = Cli::try_parse_from(["", "--profile=missing"]).unwrap();
+    assert!(Config::resolve_with(cli).is_err());
+
+    drop(env_guard);
+}
+
+#[test]
+fn test_uuid() {
+    let uuid = "0b2a9e8c-1d6b-4a5e-9a57-3f4e2b1c0d9e";
+    let cli = Cli::try_parse_from(["", "--uuid", uuid, "cat"]).unwrap();
+    assert_eq!(cli.uuid, Some(uuid.parse().unwrap()));
+    assert_eq!(cli.slug, None);
+
+    assert!(Cli::try_parse_from(["", "--uuid=not-a-uuid", "cat"]).is_err());
+    assert!(
+        Cli::try_parse_from(["", "--uuid", uuid, "--slug=foo", "cat"])
+            .is_err()
+    );
+    assert!(Cli::try_parse_from(["", "cat"]).is_err());
+
+    let env_guard = ENV_LOCK.lock().unwrap();
+    unsafe {
+        env::set_var("HOME", "/dev/null");
+    }
+    let cli = Cli::parse_from([
+        "",
+        "--url=https://hc-ping.com",
+        "--uuid",
+        uuid,
+        "cat",
+    ]);
+    let config = Config::resolve_with(cli).unwrap();
+    assert_eq!(config.check, Check::Uuid(uuid.parse().unwrap()));
+    drop(env_guard);
+}
+
+#[test]
+fn test_cli_definition() {
+    use clap::CommandFactory;
+    Cli::command().debug_assert();
+}
+
+#[test]
+fn test_batch_subcommand() {
+    let cli = Cli::try_parse_from(["", "batch", "manifest.toml"]).unwrap();
+    assert!(matches!(
+        cli.subcommand,
+        Some(SubCommand::Batch {
+            concurrency: None,
+            ..
+        })
+    ));
+    let cli =
+        Cli::try_parse_from(["", "--timeout=5", "batch", "-j3", "foo.toml"])
+            .unwrap();
+    assert_eq!(cli.timeout, Some(5));
+    assert!(matches!(
+        cli.subcommand,
+        Some(SubCommand::Batch {
+            concurrency: Some(3),
+            ..
+        })
+    ));
+
+    // A command named `batch` can still be run after `--`
+    let cli = Cli::try_parse_from(["", "--slug=foo", "--", "batch"]).unwrap();
+    assert!(cli.subcommand.is_none());
+    assert_eq!(cli.command, vec!["batch"]);
+}
+
+#[test]
+fn test_provenance() {
+    let env_guard = ENV_LOCK.lock().unwrap();
+    unsafe {
+        env::remove_var("HC_RUNNER_URL");
+        env::set_var("HC_RUNNER_TIMEOUT", "30");
+    }
+    let _tmp = temp_config(
+        r#"
+        url = "https://n8henrie.com"
+        timeout = 20
+        retries = 5
+        "#,
+    );
+
+    let cli = Cli::try_parse_args(["", "--slug=test", "--retries=1", "cat"])
+        .unwrap();
+    let config = Config::resolve_with(cli).unwrap();
+    let provenance = &config.provenance;
+    assert_eq!(provenance.files.len(), 1);
+    assert!(provenance.source("url").starts_with("config file"));
+    assert_eq!(provenance.source("timeout"), "environment");
+    assert_eq!(provenance.source("retries"), "command line");
+    assert_eq!(provenance.source("slug"), "command line");
+    assert_eq!(provenance.source("body_limit"), "default");
+    assert!(
+        config
+            .settings()
+            .iter()
+            .any(|(key, value)| *key == "retries" && value == "1")
+    );
+
+    unsafe {
+        env::remove_var("HC_RUNNER_TIMEOUT");
+    }
+    drop(env_guard);
+}
+
+#[test]
+fn test_url_sources() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let env_guard = ENV_LOCK.lock().unwrap();
+    unsafe {
+        env::remove_var("HC_RUNNER_URL");
+        env::remove_var("HC_RUNNER_URL_FILE");
+    }
+    let secrets = tempdir().unwrap();
+    let url_file = secrets.path().join("url");
+    fs::write(&url_file, "https://hc-ping.com/from-file\n").unwrap();
+    fs::set_permissions(&url_file, fs::Permissions::from_mode(0o600)).unwrap();
+
+    let _tmp = temp_config(
+        r#"
+        url = "https://hc-ping.com/from-config"
+        url_command = "echo https://hc-ping.com/from-command"
+        "#,
+    );
+    let cli = || Cli::try_parse_args(["", "--slug=test", "cat"]).unwrap();
+    let err = Config::resolve_with(cli()).unwrap_err();
+    assert!(err.to_string().contains("mutually exclusive"));
+
+    // Sources with higher precedence win
+    let _tmp = temp_config(
+        r#"url_command = "echo https://hc-ping.com/from-command""#,
+    );
+    let config = Config::resolve_with(cli()).unwrap();
+    assert_eq!(config.url.as_str(), "https://hc-ping.com/from-command");
+    assert!(config.provenance.source("url").starts_with("config file"));
+
+    unsafe {
+        env::set_var("HC_RUNNER_URL_FILE", &url_file);
+    }
+    let config = Config::resolve_with(cli()).unwrap();
+    assert_eq!(config.url.as_str(), "https://hc-ping.com/from-file");
+    assert_eq!(config.provenance.source("url"), "environment");
+
+    let cli = Cli::try_parse_args([
+        "",
+        "--slug=test",
+        "--url=https://hc-ping.com/from-cli",
+        "cat",
+    ])
+    .unwrap();
+    let config = Config::resolve_with(cli).unwrap();
+    assert_eq!(config.url.as_str(), "https://hc-ping.com/from-cli");
+
+    // The URL contains the ping key, so it shouldn't be readable by everyone
+    fs::set_permissions(&url_file, fs::Permissions::from_mode(0o644)).unwrap();
+    let cli = Cli::try_parse_args(["", "--slug=test", "cat"]).unwrap();
+    let err = Config::resolve_with(cli).unwrap_err();
+    assert!(err.to_string().contains("readable by all users"));
+
+    unsafe {
+        env::remove_var("HC_RUNNER_URL_FILE");
+    }
+    let _tmp = temp_config(r#"url_command = "false""#);
+    let cli = Cli::try_parse_args(["", "--slug=test", "cat"]).unwrap();
+    assert!(Config::resolve_with(cli).is_err());
+
+    drop(env_guard);
+}
+
+#[test]
+fn test_strict_permissions() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let env_guard = ENV_LOCK.lock().unwrap();
+    unsafe {
+        env::remove_var("HC_RUNNER_URL");
+    }
+    let _tmp = temp_config(r#"url = "https://hc-ping.com/key""#);
+    let cli = Cli::try_parse_args(["", "--slug=test", "cat"]).unwrap();
+    let path = Config::resolve_with(cli.clone())
+        .unwrap()
+        .provenance
+        .files
+        .pop()
+        .unwrap();
+    let chmod = |mode| {
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+    };
+
+    // Only a warning by default
+    chmod(0o640);
+    assert!(!Config::resolve_with(cli).unwrap().strict_permissions);
+
+    let strict = Cli::try_parse_args([
+        "",
+        "--strict-permissions",
+        "--slug=test",
+        "cat",
+    ])
+    .unwrap();
+    let err = Config::resolve_with(strict.clone()).unwrap_err();
+    assert!(err.to_string().contains("readable by group or others"));
+    chmod(0o600);
+    assert!(Config::resolve_with(strict).unwrap().strict_permissions);
+
+    // Can also be set in the config file itself
+    fs::write(
+        &path,
+        "url = \"https://hc-ping.com/key\"\nstrict_permissions = true\n",
+    )
+    .unwrap();
+    chmod(0o644);
+    let cli = Cli::try_parse_args(["", "--slug=test", "cat"]).unwrap();
+    assert!(Config::resolve_with(cli).is_err());
+
+    drop(env_guard);
+}
+
+#[test]
+fn test_negation_flags() {
+    let env_guard = ENV_LOCK.lock().unwrap();
+    unsafe {
+        env::remove_var("HC_RUNNER_URL");
+        env::set_var("HC_RUNNER_SHELL", "true");
+        env::set_var("HC_RUNNER_VERBOSE", "2");
+    }
+    let _tmp = temp_config(
+        r#"
+        url = "https://n8henrie.com"
+        success_only = true
+        normalize_exit_code = true
+        lock = "fail"
+        success_codes = [3]
+        "#,
+    );
+
+    let cli = Cli::try_parse_args(["", "--slug=test", "cat"]).unwrap();
+    let config = Config::resolve_with(cli).unwrap();
+    assert!(config.success_only);
+    assert!(config.normalize_exit_code);
+    assert_eq!(config.lock, Some(LockPolicy::Fail));
+    assert_eq!(config.success_codes, [3]);
+    assert!(config.shell.is_some());
+    assert_eq!(config.verbosity, Level::DEBUG);
+
+    let cli = Cli::try_parse_args([
+        "",
+        "--no-success-only",
+        "--no-shell",
+        "--normalize-exit-code",
+        "--no-normalize-exit-code",
+        "--no-lock",
+        "--no-success-codes",
+        "-q",
+        "--slug=test",
+        "cat",
+    ])
+    .unwrap();
+    let config = Config::resolve_with(cli).unwrap();
+    assert!(!config.success_only);
+    assert!(!config.normalize_exit_code);
+    assert!(config.shell.is_none());
+    assert_eq!(config.lock, None);
+    assert!(config.success_codes.is_empty());
+    assert_eq!(config.verbosity, Level::WARN);
+    assert_eq!(config.provenance.source("success_only"), "command line");
+    assert_eq!(config.provenance.source("lock"), "command line");
+    assert_eq!(config.provenance.source("shell"), "command line");
+
+    // The last of a flag and its negation wins
+    let cli = Cli::try_parse_args([
+        "",
+        "--no-success-only",
+        "--success-only",
+        "--slug=test",
+        "cat",
+    ])
+    .unwrap();
+    assert!(Config::resolve_with(cli).unwrap().success_only);
+
+    unsafe {
+        env::remove_var("HC_RUNNER_SHELL");
+        env::remove_var("HC_RUNNER_VERBOSE");
+    }
+    drop(env_guard);
+}
+
+#[test]
+fn test_config_formats() {
+    let env_guard = ENV_LOCK.lock().unwrap();
+    unsafe {
+        env::remove_var("HC_RUNNER_URL");
+        env::remove_var("HC_RUNNER_TIMEOUT");
+    }
+    let cli = || Cli::try_parse_args(["", "--slug=test", "cat"]).unwrap();
+    let home = temp_config(r#"url = "https://n8henrie.com/toml""#);
+    let toml = Config::resolve_with(cli())
+        .unwrap()
+        .provenance
+        .files
+        .pop()
+        .unwrap();
+    let dir = toml.parent().unwrap();
+
+    fs::write(dir.join("config.yml"), "url: https://n8henrie.com/yaml\n")
+        .unwrap();
+    let err = Config::resolve_with(cli()).unwrap_err().to_string();
+    assert!(err.contains("multiple config files"));
+    assert!(err.contains("config.toml") && err.contains("config.yml"));
+
+    fs::remove_file(&toml).unwrap();
+    let config = Config::resolve_with(cli()).unwrap();
+    assert_eq!(config.url.as_str(), "https://n8henrie.com/yaml");
+
+    // `--config` is parsed according to its extension
+    let json = home.path().join("hc.json");
+    fs::write(
+        &json,
+        r#"{"url": "https://n8henrie.com/json", "timeout": 5}"#,
+    )
+    .unwrap();
+    let mut cli = cli();
+    cli.config = Some(json);
+    let config = Config::resolve_with(cli).unwrap();
+    assert_eq!(config.url.as_str(), "https://n8henrie.com/json");
+    assert_eq!(config.timeout, 5);
+
+    drop(env_guard);
+}
+
+#[test]
+fn test_config_layers() {
+    let env_guard = ENV_LOCK.lock().unwrap();
+    unsafe {
+        env::remove_var("HC_RUNNER_URL");
+        env::remove_var("HC_RUNNER_TIMEOUT");
+        env::remove_var("HC_RUNNER_RETRIES");
+    }
+    let write = |dir: &Path, contents: &str| {
+        fs::create_dir_all(dir.join("hc-runner")).unwrap();
+        fs::write(dir.join("hc-runner").join("config.toml"), contents)
+            .unwrap();
+    };
+    // The first of `XDG_CONFIG_DIRS` takes precedence
+    let (first, second) = (tempdir().unwrap(), tempdir().unwrap());
+    write(
+        second.path(),
+        r#"
+        url = "https://n8henrie.com"
+        timeout = 1
+        retries = 1
+        body_limit = 1
+        "#,
+    );
+    write(first.path(), "timeout = 2\nretries = 2\n");
+    unsafe {
+        env::set_var(
+            "XDG_CONFIG_DIRS",
+            env::join_paths([first.path(), second.path()]).unwrap(),
+        );
+    }
+    let home = temp_config("retries = 3");
+    let extra = home.path().join("extra.toml");
+    fs::write(&extra, "body_limit = 4").unwrap();
+
+    let mut cli = Cli::try_parse_args(["", "--slug=test", "cat"]).unwrap();
+    cli.config = Some(extra.clone());
+    let config = Config::resolve_with(cli).unwrap();
+    assert_eq!(config.url.as_str(), "https://n8henrie.com/");
+    assert_eq!(config.timeout, 2);
+    assert_eq!(config.retries, 3);
+    assert_eq!(config.body_limit, 4);
+
+    let files = &config.provenance.files;
+    assert_eq!(files.len(), 4);
+    assert!(files[0].starts_with(second.path()));
+    assert!(files[1].starts_with(first.path()));
+    assert!(files[2].starts_with(home.path()));
+    assert_eq!(files[3], extra);
+    assert_eq!(
+        config.provenance.source("timeout"),
+        format!("config file `{}`", files[1].display())
+    );
+
+    unsafe {
+        env::remove_var("XDG_CONFIG_DIRS");
+    }
+    drop(env_guard);
+}
+
+#[test]
+fn test_check_file() {
+    use inspect::check_file;
+
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("config.toml");
+    fs::write(
+        &path,
+        r#"
+        url = "https://n8henrie.com"
+        timout = 10
+        retries = "many"
+
+        [checks.backup]
+        slug = "nightly-backup"
+        log_fd = 1
+        sulg = "typo"
+        "#,
+    )
+    .unwrap();
+    let problems = check_file(&path);
+    assert_eq!(problems.len(), 4, "{problems:?}");
+    for expected in [
+        "unknown key `timout`",
+        "`retries`",
+        "`checks.backup.log_fd` must be 3 or greater",
+        "unknown key `checks.backup.sulg`",
+    ] {
+        assert!(
+            problems.iter().any(|problem| problem.contains(expected)),
+            "{expected} not in {problems:?}"
+        );
+    }
+
+    fs::write(&path, "url = \"https://n8henrie.com\"\ntimeout = 5\n").unwrap();
+    assert!(check_file(&path).is_empty());
+    // config-rs can't read files with other extensions
+    let path = dir.path().join("config.conf");
+    fs::write(&path, "url = \"https://n8henrie.com\"\n").unwrap();
+    let problems = check_file(&path);
+    assert_eq!(problems.len(), 1, "{problems:?}");
+    assert!(problems[0].contains("unsupported config file extension"));
+
+    // Nothing to read
+    assert!(check_file(Path::new("/dev/null")).is_empty());
+}
+
+#[test]
+fn test_template() {
+    // Every setting in the template is valid once uncommented
+    let template = include_str!("template.toml");
+    let uncommented: String = template
+        .lines()
+        .map(|line| {
+            let setting = line.strip_prefix("# ").filter(|line| {
+                line.starts_with('[')
+                    || line.split_once(" = ").is_some_and(|(key, _)| {
+                        key.chars().all(|c| c.is_ascii_lowercase() || c == '_')
+                    })
+            });
+            setting.unwrap_or(line).to_string() + "\n"
+        })
+        .collect();
+    assert!(uncommented.contains("\nurl = "));
+    assert!(uncommented.contains("\n[checks.backup]\n"));
+    // ... and includes every setting, other than the check's UUID
+    for key in inspect::known_keys() {
+        assert!(
+            *key == "uuid" || uncommented.contains(&format!("\n{key} = ")),
+            "{key} missing from template"
+        );
+    }
+
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("config.toml");
+    fs::write(&path, uncommented).unwrap();
+    assert_eq!(inspect::check_file(&path), Vec::<String>::new());
+}
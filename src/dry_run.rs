@@ -0,0 +1,67 @@
+use std::io::{self, Write};
+
+use crate::config::{Check, Provenance};
+use crate::ping::Pinger;
+use crate::redact::redact;
+use crate::{Config, Result, exec};
+
+/// Write the config files that were read, followed by the value of each
+/// setting and where it came from.
+pub(crate) fn write_settings(
+    out: &mut impl Write,
+    provenance: &Provenance,
+    settings: &[(&str, String)],
+) -> Result<()> {
+    let files = &provenance.files;
+    if files.is_empty() {
+        writeln!(out, "config files: none")?;
+    } else {
+        writeln!(out, "config files, later overriding earlier:")?;
+        for file in files {
+            writeln!(out, "  {}", file.display())?;
+        }
+    }
+
+    let width = settings
+        .iter()
+        .map(|(key, _)| key.len())
+        .max()
+        .unwrap_or_default();
+    writeln!(out)?;
+    for (key, value) in settings {
+        let source = provenance.source(key);
+        writeln!(out, "{key:width$}  {value}  ({source})")?;
+    }
+    Ok(())
+}
+
+/// Print what would be done for `config` without running the command or
+/// pinging healthchecks, with secrets redacted.
+pub(crate) fn print(config: &Config, pinger: &Pinger) -> Result<u8> {
+    let mut out = Vec::new();
+    write_settings(&mut out, &config.provenance, &config.settings())?;
+
+    let mut start = pinger.url("start")?;
+    if let Check::Slug(_) = config.check {
+        start.query_pairs_mut().append_pair("create", "1");
+    }
+    writeln!(out)?;
+    if config.success_only {
+        writeln!(out, "start: not sent (success_only)")?;
+    } else {
+        writeln!(out, "start: HEAD {start}")?;
+    }
+    writeln!(
+        out,
+        "end:   POST {} (with the exit code in place of 0)",
+        pinger.url("0")?
+    )?;
+
+    let mut command = exec::command(config)?;
+    command.env("HC_RUNNER_RID", pinger.rid().to_string());
+    writeln!(out, "command: {command:?}")?;
+
+    io::stdout()
+        .write_all(redact(&String::from_utf8_lossy(&out)).as_bytes())?;
+    Ok(0)
+}
@@ -0,0 +1,482 @@
+use std::ffi::OsString;
+use std::io::{self, IsTerminal};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::process::{ChildStderr, ChildStdout};
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::capture::{BodyFormat, BodySource, Capture};
+use crate::progress::{self, LineMatcher};
+use crate::{Config, Error, Result, TIMEOUT_EXIT_CODE, signals};
+
+/// The outcome of running the command
+#[derive(Debug)]
+pub(crate) struct Execution {
+    pub(crate) status: ExitStatus,
+    /// Set if the command was terminated for exceeding `--run-timeout`
+    pub(crate) timed_out: Option<Duration>,
+    pub(crate) started: SystemTime,
+    pub(crate) duration: Duration,
+    pub(crate) usage: Usage,
+    /// Captured output to be sent in the body of the end ping
+    pub(crate) output: Output,
+}
+
+/// Resource usage of the command (including any descendants it waited for)
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Usage {
+    /// Peak resident set size in bytes
+    pub(crate) max_rss: u64,
+    pub(crate) user_time: Duration,
+    pub(crate) system_time: Duration,
+}
+
+/// Captured output of the stream(s) selected by `--body-source`
+#[derive(Debug)]
+pub(crate) enum Output {
+    /// Interleaved in the order it was received, for `--body-format=text`
+    Combined(Vec<u8>),
+    /// Kept separate, for `--body-format=json`
+    Separate {
+        stdout: Option<Vec<u8>>,
+        stderr: Option<Vec<u8>>,
+    },
+}
+
+impl Execution {
+    /// The exit code to report for the command, following the conventions of
+    /// the shell (and coreutils' `timeout`) for commands that did not exit
+    /// normally.
+    pub(crate) fn exit_code(&self) -> Result<i32> {
+        if self.timed_out.is_some() {
+            Ok(TIMEOUT_EXIT_CODE.into())
+        } else if let Some(code) = self.status.code() {
+            Ok(code)
+        } else if let Some(signo) = self.status.signal() {
+            Ok(128 + signo)
+        } else {
+            Err(Error::EmptyExitCode)
+        }
+    }
+
+    /// An explanation of how the command ended if it did not exit normally
+    pub(crate) fn note(&self) -> Option<String> {
+        if let Some(run_timeout) = self.timed_out {
+            Some(format!(
+                "hc-runner: command timed out after {run_timeout:?} and was \
+                terminated"
+            ))
+        } else {
+            let signo = self.status.signal()?;
+            let core = if self.status.core_dumped() {
+                " (core dumped)"
+            } else {
+                ""
+            };
+            Some(format!(
+                "hc-runner: command was killed by {}{core}",
+                signal_name(signo)
+            ))
+        }
+    }
+}
+
+/// The exit code a shell would use for a command that could not be started
+/// due to `err`: 127 if it was not found, otherwise 126.
+pub(crate) fn spawn_exit_code(err: &io::Error) -> u8 {
+    if err.kind() == io::ErrorKind::NotFound {
+        127
+    } else {
+        126
+    }
+}
+
+/// The conventional name of signal `signo`, e.g. `SIGTERM`
+pub(crate) fn signal_name(signo: libc::c_int) -> String {
+    let name = match signo {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGUSR1 => "SIGUSR1",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGUSR2 => "SIGUSR2",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGXCPU => "SIGXCPU",
+        libc::SIGXFSZ => "SIGXFSZ",
+        _ => return format!("signal {signo}"),
+    };
+    name.into()
+}
+
+/// Send signal `signo` to `target`, which is the child's pid, or its
+/// negation for the child's process group (i.e. the command and any
+/// processes it started, unless they left the group). It is not an error
+/// for the target to be gone already.
+fn kill(target: libc::pid_t, signo: libc::c_int) -> Result<()> {
+    // SAFETY: `kill` has no memory safety requirements; callers only pass
+    // our own child, whose pid (and group) can't be reused until it is
+    // reaped (and the group is empty).
+    if unsafe { libc::kill(target, signo) } != 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ESRCH) {
+            return Err(err.into());
+        }
+    }
+    Ok(())
+}
+
+/// Build the `Command` to be run for `config`.
+pub(crate) fn command(config: &Config) -> Result<Command> {
+    // With `--shell`, the command's arguments are joined into a single
+    // script so that pipes, redirects, etc. are interpreted by the shell
+    let words: Vec<OsString> = if let Some(shell) = &config.shell {
+        let mut script = config.command.join(" ");
+        if shell.pipefail {
+            script = format!("set -o pipefail\n{script}");
+        }
+        vec![shell.path.clone().into(), "-c".into(), script.into()]
+    } else {
+        config.command.iter().map(Into::into).collect()
+    };
+
+    if cfg!(target_os = "macos") {
+        let mut command = Command::new("/usr/bin/caffeinate");
+        command.args(words);
+        Ok(command)
+    } else {
+        let (cmd, args) = words
+            .split_first()
+            .ok_or_else(|| Error::Config("command was empty".into()))?;
+        let mut command = Command::new(cmd);
+        command.args(args);
+        Ok(command)
+    }
+}
+
+/// Copy everything from `reader` to `writer` as soon as it is available,
+/// optionally keeping a copy of the data that passed through and looking for
+/// progress lines.
+async fn tee<R, W>(
+    mut reader: R,
+    mut writer: W,
+    capture: Option<Arc<Mutex<Capture>>>,
+    mut lines: Option<LineMatcher>,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = [0; 8192];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        writer.write_all(&buf[..n]).await?;
+        writer.flush().await?;
+        if let Some(capture) = &capture {
+            capture
+                .lock()
+                .map_err(|_| io::Error::other("capture lock poisoned"))?
+                .push(&buf[..n]);
+        }
+        if let Some(lines) = &mut lines {
+            lines.push(&buf[..n]);
+        }
+    }
+    if let Some(lines) = &mut lines {
+        lines.finish();
+    }
+    Ok(())
+}
+
+/// Arrange for `writer` to be available to the child as file descriptor
+/// `fd`.
+fn pass_fd(command: &mut Command, writer: &impl AsRawFd, fd: RawFd) {
+    let writer = writer.as_raw_fd();
+    // SAFETY: only async-signal-safe functions are called between `fork` and
+    // `exec`.
+    unsafe {
+        command.pre_exec(move || {
+            // `dup2` is a no-op if the descriptors are equal, which would
+            // leave close-on-exec set
+            let res = if writer == fd {
+                libc::fcntl(fd, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(writer, fd)
+            };
+            if res == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Waits for the child in a blocking task, see `reap`
+type Reaper = JoinHandle<io::Result<(ExitStatus, Usage)>>;
+
+/// Block until the child `pid` exits, reaping it with `wait4` in order to
+/// collect its resource usage (which is lost if it is reaped any other way).
+fn reap(pid: libc::pid_t) -> io::Result<(ExitStatus, Usage)> {
+    let mut status = 0;
+    // SAFETY: `rusage` is a plain C struct for which all zeroes is valid
+    let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+    // SAFETY: both pointers are valid for the duration of the call
+    while unsafe { libc::wait4(pid, &raw mut status, 0, &raw mut rusage) }
+        == -1
+    {
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+
+    let duration = |tv: libc::timeval| {
+        Duration::from_secs(tv.tv_sec.try_into().unwrap_or_default())
+            + Duration::from_micros(tv.tv_usec.try_into().unwrap_or_default())
+    };
+    // `ru_maxrss` is in kilobytes on Linux but bytes on macOS
+    let scale = if cfg!(target_os = "macos") { 1 } else { 1024 };
+    let usage = Usage {
+        max_rss: u64::try_from(rusage.ru_maxrss).unwrap_or_default() * scale,
+        user_time: duration(rusage.ru_utime),
+        system_time: duration(rusage.ru_stime),
+    };
+    Ok((ExitStatus::from_raw(status), usage))
+}
+
+/// Ask `target` (see `kill`) to exit with SIGTERM, escalating to SIGKILL if
+/// the child is still running after `grace`.
+async fn terminate(
+    target: libc::pid_t,
+    reaper: &mut Reaper,
+    grace: Duration,
+) -> Result<(ExitStatus, Usage)> {
+    kill(target, libc::SIGTERM)?;
+    if let Ok(res) = tokio::time::timeout(grace, &mut *reaper).await {
+        return Ok(res??);
+    }
+    warn!("command still running after SIGTERM, sending SIGKILL");
+    kill(target, libc::SIGKILL)?;
+    Ok(reaper.await??)
+}
+
+/// Wait for the child to exit, passing on any signals received by
+/// `hc-runner` in the meantime (e.g. from `systemctl stop`) so that the
+/// command can shut down cleanly and its fate is reported to healthchecks.
+async fn wait(
+    target: libc::pid_t,
+    reaper: &mut Reaper,
+) -> Result<(ExitStatus, Usage)> {
+    let mut signals = signals::subscribe();
+    loop {
+        tokio::select! {
+            res = &mut *reaper => return Ok(res??),
+            _ = signals.changed() => (),
+        }
+        let signo = *signals.borrow_and_update();
+        info!("forwarding {} to command", signal_name(signo));
+        if !reaper.is_finished() {
+            kill(target, signo)?;
+        }
+    }
+}
+
+/// How long to keep copying the command's output after it exits, in case a
+/// process it left running in the background still holds the pipes open
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Wait for `task` to finish reading the command's `stream` until
+/// `deadline`, then give up on it.
+async fn drain(
+    mut task: JoinHandle<io::Result<()>>,
+    stream: &str,
+    deadline: tokio::time::Instant,
+) -> Result<()> {
+    if let Ok(res) = tokio::time::timeout_at(deadline, &mut task).await {
+        return Ok(res??);
+    }
+    warn!("command exited but its {stream} is still open, not waiting for it");
+    task.abort();
+    // Once cancelled, the task no longer holds the capture
+    let _ = task.await;
+    Ok(())
+}
+
+type Shared = Arc<Mutex<Capture>>;
+
+/// Create the captures for the child's stdout and stderr according to
+/// `--body-source` and `--body-format`.
+fn captures(config: &Config) -> (Option<Shared>, Option<Shared>) {
+    let new =
+        |limit| Arc::new(Mutex::new(Capture::new(limit, config.truncate)));
+    let limit = config.body_limit;
+    match (config.body_source, config.body_format) {
+        (BodySource::Stdout, _) => (Some(new(limit)), None),
+        (BodySource::Stderr, _) => (None, Some(new(limit))),
+        (BodySource::Both, BodyFormat::Text) => {
+            let capture = new(limit);
+            (Some(capture.clone()), Some(capture))
+        }
+        (BodySource::Both, BodyFormat::Json) => {
+            (Some(new(limit / 2)), Some(new(limit - limit / 2)))
+        }
+    }
+}
+
+fn into_body(capture: Shared) -> Result<Vec<u8>> {
+    Ok(Arc::into_inner(capture)
+        .ok_or(Error::Unknown)?
+        .into_inner()
+        .map_err(|_| Error::Unknown)?
+        .into_body())
+}
+
+/// Collect the captured output once the streams have been closed.
+fn output(
+    format: BodyFormat,
+    stdout: Option<Shared>,
+    stderr: Option<Shared>,
+) -> Result<Output> {
+    Ok(match format {
+        BodyFormat::Text => {
+            // Both may refer to the same capture
+            let capture = if let Some(stdout) = stdout {
+                drop(stderr);
+                stdout
+            } else {
+                stderr.ok_or(Error::Unknown)?
+            };
+            Output::Combined(into_body(capture)?)
+        }
+        BodyFormat::Json => Output::Separate {
+            stdout: stdout.map(into_body).transpose()?,
+            stderr: stderr.map(into_body).transpose()?,
+        },
+    })
+}
+
+/// Spawn `command` and wait for it to finish (or time out).
+///
+/// The child's output is piped through to our own stdout and stderr as it is
+/// produced (instead of after the child exits) so that long-running commands
+/// show progress in e.g. journald; the stream(s) selected by `--body-source`
+/// are also captured to be used as the body of the end ping.
+///
+/// Progress lines (those matching `--log-pattern` and everything written to
+/// `--log-fd`) are sent to `progress`.
+pub(crate) async fn execute(
+    mut command: Command,
+    config: &Config,
+    progress: Option<Sender<String>>,
+) -> Result<Execution> {
+    let log_fd = match (config.log_fd, &progress) {
+        (Some(fd), Some(tx)) => {
+            let (reader, writer) = io::pipe()?;
+            pass_fd(&mut command, &writer, fd);
+            Some((reader, writer, tx.clone()))
+        }
+        _ => None,
+    };
+
+    let started = SystemTime::now();
+    let clock = Instant::now();
+    // In its own process group, so that the whole group can be signalled
+    // (e.g. a script and the commands it runs). Not when run from a
+    // terminal, which would stop a background group that reads from it.
+    let own_group = !io::stdin().is_terminal();
+    if own_group {
+        command.process_group(0);
+    }
+    let mut child = command
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|source| Error::Spawn {
+            program: command.get_program().to_string_lossy().into(),
+            source,
+        })?;
+    let pid = libc::pid_t::try_from(child.id())?;
+    let mut reaper = tokio::task::spawn_blocking(move || reap(pid));
+    let target = if own_group { -pid } else { pid };
+
+    // Our copy of the write end must be closed for the reader to see EOF
+    let log_fd = log_fd.map(|(reader, writer, tx)| {
+        drop(writer);
+        tokio::spawn(progress::read_fd(reader, tx))
+    });
+    let lines = || {
+        config
+            .log_pattern
+            .clone()
+            .zip(progress.clone())
+            .map(|(pattern, tx)| LineMatcher::new(pattern, tx))
+    };
+    let stdout =
+        ChildStdout::from_std(child.stdout.take().ok_or(Error::Unknown)?)?;
+    let stderr =
+        ChildStderr::from_std(child.stderr.take().ok_or(Error::Unknown)?)?;
+    let (stdout_capture, stderr_capture) = captures(config);
+    let stdout = tokio::spawn(tee(
+        stdout,
+        tokio::io::stdout(),
+        stdout_capture.clone(),
+        lines(),
+    ));
+    let stderr = tokio::spawn(tee(
+        stderr,
+        tokio::io::stderr(),
+        stderr_capture.clone(),
+        lines(),
+    ));
+    drop(progress);
+
+    let mut timed_out = None;
+    let waiting = wait(target, &mut reaper);
+    let (status, usage) = if let Some(run_timeout) = config.run_timeout {
+        let run_timeout = Duration::from_secs(run_timeout);
+        if let Ok(res) = tokio::time::timeout(run_timeout, waiting).await {
+            res?
+        } else {
+            warn!("command timed out after {run_timeout:?}, terminating");
+            timed_out = Some(run_timeout);
+            let grace = Duration::from_secs(config.kill_after);
+            terminate(target, &mut reaper, grace).await?
+        }
+    } else {
+        waiting.await?
+    };
+    let duration = clock.elapsed();
+    let deadline = tokio::time::Instant::now() + DRAIN_TIMEOUT;
+    drain(stdout, "stdout", deadline).await?;
+    drain(stderr, "stderr", deadline).await?;
+    if let Some(log_fd) = log_fd {
+        drain(log_fd, "log fd", deadline).await?;
+    }
+
+    Ok(Execution {
+        status,
+        timed_out,
+        started,
+        duration,
+        usage,
+        output: output(config.body_format, stdout_capture, stderr_capture)?,
+    })
+}
@@ -0,0 +1,227 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use humantime::format_rfc3339_millis;
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use tracing::{Level, info, warn};
+use uuid::Uuid;
+
+use crate::capture::Capture;
+use crate::lock::flock;
+use crate::ping::Pinger;
+use crate::{Config, Error, Result, Truncation, project_dirs};
+
+/// How much of each run's output is kept in the history
+const OUTPUT_LIMIT: usize = 4096;
+
+/// A query of the local run history for `hc-runner history`
+#[derive(Debug)]
+pub struct Query {
+    pub(crate) check: Option<String>,
+    pub(crate) failed: bool,
+    pub(crate) json: bool,
+    pub(crate) limit: Option<usize>,
+    pub verbosity: Level,
+}
+
+/// What happened to the end ping of a run
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Ping {
+    Sent,
+    /// Not sent, e.g. due to `--success-only`
+    Skipped,
+    /// The request failed or was rejected by the server
+    Failed(String),
+}
+
+/// A single run of a check, stored as one line of JSON
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) struct Record {
+    pub(crate) check: String,
+    pub(crate) rid: Uuid,
+    pub(crate) start: String,
+    pub(crate) duration_secs: f64,
+    pub(crate) exit_code: i32,
+    pub(crate) reported_code: i32,
+    pub(crate) ping: Ping,
+    pub(crate) output: String,
+}
+
+impl Record {
+    /// A run of `config`'s check, before its end ping has been sent
+    pub(crate) fn new(
+        config: &Config,
+        pinger: &Pinger,
+        started: SystemTime,
+        duration: Duration,
+        exit_code: i32,
+        reported_code: i32,
+    ) -> Self {
+        Self {
+            check: config.check.to_string(),
+            rid: pinger.rid(),
+            start: format_rfc3339_millis(started).to_string(),
+            duration_secs: duration.as_secs_f64(),
+            exit_code,
+            reported_code,
+            ping: Ping::Skipped,
+            output: String::new(),
+        }
+    }
+
+    fn failed(&self) -> bool {
+        self.reported_code != 0 || matches!(self.ping, Ping::Failed(_))
+    }
+}
+
+/// Keep the beginning and end of a run's end ping body for the history.
+pub(crate) fn excerpt(body: &[u8]) -> String {
+    let mut capture = Capture::new(OUTPUT_LIMIT, Truncation::HeadTail);
+    capture.push(body);
+    String::from_utf8_lossy(&capture.into_body()).into()
+}
+
+fn path() -> Result<PathBuf> {
+    Ok(project_dirs()?.data_dir().join("history.jsonl"))
+}
+
+fn read(path: &Path) -> Result<Vec<Record>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut records = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        match serde_json::from_str(&line) {
+            Ok(record) => records.push(record),
+            Err(e) => warn!("skipping unreadable history entry: {e}"),
+        }
+    }
+    Ok(records)
+}
+
+/// Drop all but the newest `limit` records.
+fn prune(records: &mut Vec<Record>, limit: usize) {
+    records.drain(..records.len().saturating_sub(limit));
+}
+
+/// Append `record` to the history at `path`, keeping at most `limit` records.
+fn append(path: &Path, record: &Record, limit: usize) -> Result<()> {
+    let dir = path.parent().ok_or(Error::Unknown)?;
+    fs::create_dir_all(dir)?;
+
+    // Serializes concurrent runs; the history itself is replaced when pruned
+    let lock = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .mode(0o600)
+        .open(path.with_extension("lock"))?;
+    flock(&lock, true)?;
+
+    let mut file = File::options()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)?;
+    writeln!(file, "{}", serde_json::to_string(record)?)?;
+    drop(file);
+
+    let mut records = read(path)?;
+    if records.len() > limit {
+        prune(&mut records, limit);
+        let mut tmp = NamedTempFile::new_in(dir)?;
+        for record in &records {
+            writeln!(tmp, "{}", serde_json::to_string(record)?)?;
+        }
+        tmp.persist(path).map_err(|e| e.error)?;
+    }
+    Ok(())
+}
+
+/// Record a run in the history, keeping at most `limit` records (none if
+/// `limit` is 0). Problems with the history are logged rather than returned
+/// so that they never affect the outcome of the run.
+pub(crate) async fn save(record: Record, limit: usize) {
+    if limit == 0 {
+        return;
+    }
+    let res = tokio::task::spawn_blocking(move || {
+        let path = path()?;
+        info!("recording run in {}", path.display());
+        append(&path, &record, limit)
+    })
+    .await;
+    match res {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => warn!("failed to record run in history: {e}"),
+        Err(e) => warn!("failed to record run in history: {e}"),
+    }
+}
+
+fn matches(record: &Record, query: &Query) -> bool {
+    query
+        .check
+        .as_ref()
+        .is_none_or(|check| &record.check == check)
+        && (!query.failed || record.failed())
+}
+
+/// Print the runs in the history that match `query`, oldest first.
+///
+/// # Errors
+/// Returns an error if the history could not be read
+pub fn show(query: &Query) -> Result<u8> {
+    let mut records: Vec<_> = read(&path()?)?
+        .into_iter()
+        .filter(|record| matches(record, query))
+        .collect();
+    if let Some(limit) = query.limit {
+        prune(&mut records, limit);
+    }
+
+    let mut stdout = io::stdout().lock();
+    if query.json {
+        serde_json::to_writer_pretty(&mut stdout, &records)?;
+        writeln!(stdout)?;
+        return Ok(0);
+    }
+
+    let width = records
+        .iter()
+        .map(|record| record.check.len())
+        .chain(["CHECK".len()])
+        .max()
+        .unwrap_or_default();
+    writeln!(
+        stdout,
+        "{:24}  {:width$}  {:>6}  {:>10}  PING",
+        "START", "CHECK", "EXIT", "DURATION"
+    )?;
+    for record in &records {
+        let ping = match &record.ping {
+            Ping::Sent => "sent",
+            Ping::Skipped => "skipped",
+            Ping::Failed(_) => "failed",
+        };
+        writeln!(
+            stdout,
+            "{:24}  {:width$}  {:>6}  {:>10}  {ping}",
+            record.start,
+            record.check,
+            record.exit_code,
+            format!("{:.1}s", record.duration_secs),
+        )?;
+    }
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,68 @@
+use super::*;
+
+fn record(check: &str, reported_code: i32, ping: Ping) -> Record {
+    Record {
+        check: check.into(),
+        rid: Uuid::new_v4(),
+        start: "2024-01-01T00:00:00.000Z".into(),
+        duration_secs: 1.5,
+        exit_code: reported_code,
+        reported_code,
+        ping,
+        output: String::new(),
+    }
+}
+
+fn query(check: Option<&str>, failed: bool) -> Query {
+    Query {
+        check: check.map(Into::into),
+        failed,
+        json: false,
+        limit: None,
+        verbosity: Level::WARN,
+    }
+}
+
+#[test]
+fn test_append_prunes_oldest() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("nested").join("history.jsonl");
+    for code in 0..5 {
+        append(&path, &record("foo", code, Ping::Sent), 3).unwrap();
+    }
+
+    let codes: Vec<_> = read(&path)
+        .unwrap()
+        .into_iter()
+        .map(|record| record.exit_code)
+        .collect();
+    assert_eq!(codes, [2, 3, 4]);
+}
+
+#[test]
+fn test_read_missing_is_empty() {
+    let dir = tempfile::tempdir().unwrap();
+    assert!(read(&dir.path().join("history.jsonl")).unwrap().is_empty());
+}
+
+#[test]
+fn test_matches() {
+    let ok = record("foo", 0, Ping::Sent);
+    let failed = record("foo", 1, Ping::Sent);
+    let unsent = record("bar", 0, Ping::Failed("500".into()));
+
+    assert!(matches(&ok, &query(None, false)));
+    assert!(matches(&ok, &query(Some("foo"), false)));
+    assert!(!matches(&ok, &query(Some("bar"), false)));
+    assert!(!matches(&ok, &query(None, true)));
+    assert!(matches(&failed, &query(Some("foo"), true)));
+    assert!(matches(&unsent, &query(None, true)));
+}
+
+#[test]
+fn test_excerpt() {
+    let body = "a".repeat(OUTPUT_LIMIT * 2);
+    let excerpt = excerpt(body.as_bytes());
+    assert!(excerpt.len() <= OUTPUT_LIMIT);
+    assert!(excerpt.contains("bytes elided by hc-runner"));
+}
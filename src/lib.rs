@@ -2,18 +2,55 @@
 
 use std::fmt;
 use std::io::{self, Write};
-use std::process::Command;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
-use reqwest::{Client, Url};
+use directories::ProjectDirs;
+use history::{Ping, Record};
+use reqwest::{Client, Method, Url};
+use tokio::sync::mpsc;
 use tracing::{info, warn};
+use uuid::Uuid;
 
 extern crate config as config_rs;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Exit code returned when the command is terminated by `--run-timeout`,
+/// matching coreutils' `timeout`
+pub const TIMEOUT_EXIT_CODE: u8 = 124;
+
+pub mod batch;
+
+mod capture;
+pub use capture::{BodyFormat, BodySource, Truncation};
+
 mod config;
-pub use config::Config;
+use config::Check;
+pub use config::{Action, Config, inspect};
+
+mod dry_run;
+
+mod exec;
+use exec::Execution;
+
+pub mod history;
+
+mod lock;
+pub use lock::{LOCKED_EXIT_CODE, LockPolicy};
+
+mod ping;
+use ping::{Pinger, Transport};
+
+mod progress;
+
+pub mod redact;
+
+mod report;
+
+mod signals;
+
+pub mod spool;
+use spool::Posted;
 
 #[derive(thiserror::Error)]
 pub enum Error {
@@ -40,6 +77,9 @@ pub enum Error {
     #[error("join error: {0}")]
     Join(#[from] tokio::task::JoinError),
 
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
     #[error(transparent)]
     ParseInt(#[from] std::num::ParseIntError),
 
@@ -50,8 +90,19 @@ pub enum Error {
     ParseUrl(#[from] url::ParseError),
 
     #[error(transparent)]
+    Regex(#[from] regex::Error),
+
+    /// Includes the URL of the failed request
+    #[error("{}", redact::redact(&.0.to_string()))]
     Reqwest(#[from] reqwest::Error),
 
+    /// The command could not be started
+    #[error("failed to run `{program}`: {source}")]
+    Spawn {
+        program: String,
+        source: std::io::Error,
+    },
+
     #[error(transparent)]
     TryFromInt(#[from] std::num::TryFromIntError),
 
@@ -64,11 +115,19 @@ pub enum Error {
 
 impl fmt::Debug for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{self}")
+        write!(f, "{}", redact::redact(&self.to_string()))
     }
 }
 
-fn add_slug(mut url: Url, slug: String) -> Result<Url> {
+/// Where `hc-runner` keeps its config, data, and runtime files
+fn project_dirs() -> Result<ProjectDirs> {
+    ProjectDirs::from("com", "n8henrie", "hc-runner").ok_or_else(|| {
+        Error::Config("unable to determine home directory".into())
+    })
+}
+
+/// Build the base URL for pings to `check`
+fn check_url(mut url: Url, check: &Check) -> Result<Url> {
     // Calls to `join` will only interpret the last segment of the path as a
     // directory if it has a trailing slash
     // https://docs.rs/reqwest/latest/reqwest/struct.Url.html#method.join
@@ -77,20 +136,186 @@ fn add_slug(mut url: Url, slug: String) -> Result<Url> {
         url.set_path(&(path.to_string() + "/"));
     }
 
-    let with_slug = url.join(&(slug + "/"))?;
-    Ok(with_slug)
+    let with_check = url.join(&format!("{check}/"))?;
+    Ok(with_check)
+}
+
+/// Send `body` as the end ping to `endpoint` (if any) and record the run in
+/// the history. Every run that gets as far as choosing a check ends here.
+pub(crate) async fn end(
+    config: &Config,
+    pinger: &Pinger,
+    mut record: Record,
+    endpoint: Option<&str>,
+    body: Vec<u8>,
+    format: BodyFormat,
+) -> Result<()> {
+    record.output = history::excerpt(&body);
+    let res = match endpoint {
+        Some(endpoint) => {
+            let url = pinger.url(endpoint)?;
+            info!("calling end url {}", url);
+            Some(spool::post(pinger, &config.check, url, body, format).await)
+        }
+        None => None,
+    };
+    record.ping = match &res {
+        None => Ping::Skipped,
+        Some(Ok(Posted::Delivered(res))) if res.status().is_success() => {
+            Ping::Sent
+        }
+        Some(Ok(Posted::Delivered(res))) => {
+            Ping::Failed(res.status().to_string())
+        }
+        // Spooled pings will be delivered later, so the run is otherwise
+        // unaffected
+        Some(Ok(Posted::Spooled(reason))) => Ping::Failed(reason.clone()),
+        Some(Err(e)) => Ping::Failed(e.to_string()),
+    };
+    history::save(record, config.history_limit).await;
+
+    if let Some(Posted::Delivered(res)) = res.transpose()?
+        && !res.status().is_success()
+    {
+        let text = res.text().await?;
+        writeln!(io::stderr(), "failed to update status: {text}")?;
+    }
+    Ok(())
+}
+
+/// Report a command that could not be started (which would otherwise leave
+/// the check "started" until its grace period expires), returning the exit
+/// code a shell would use.
+async fn spawn_failed(
+    config: &Config,
+    pinger: &Pinger,
+    started: SystemTime,
+    program: &str,
+    err: &io::Error,
+) -> Result<u8> {
+    let msg = format!("hc-runner: failed to run `{program}`: {err}\n");
+    io::stderr().write_all(msg.as_bytes())?;
+
+    let code = exec::spawn_exit_code(err);
+    let endpoint = match config.spawn_failure_code {
+        _ if config.success_only => None,
+        Some(code) => Some(code.to_string()),
+        None => Some("fail".into()),
+    };
+    let duration = started.elapsed().unwrap_or_default();
+    let record = Record::new(
+        config,
+        pinger,
+        started,
+        duration,
+        code.into(),
+        code.into(),
+    );
+    end(
+        config,
+        pinger,
+        record,
+        endpoint.as_deref(),
+        msg.into(),
+        BodyFormat::Text,
+    )
+    .await?;
+    Ok(code)
+}
+
+/// Send the end ping for `execution` and record the run in the history.
+async fn finish(
+    config: &Config,
+    pinger: &Pinger,
+    execution: Execution,
+    exit_code: i32,
+    reported_code: i32,
+) -> Result<()> {
+    let record = Record::new(
+        config,
+        pinger,
+        execution.started,
+        execution.duration,
+        exit_code,
+        reported_code,
+    );
+    let body = report::body(config, execution, exit_code)?;
+    let endpoint = reported_code.to_string();
+    let endpoint = match (config.success_only, reported_code) {
+        (false, _) | (true, 0) => Some(endpoint.as_str()),
+        _ => None,
+    };
+    end(config, pinger, record, endpoint, body, config.body_format).await
 }
 
 /// # Errors
 /// Returns the exit code of the command
-#[tracing::instrument]
 pub async fn run(config: Config) -> Result<u8> {
-    let url = add_slug(config.url, config.slug)?;
+    signals::listen()?;
+    run_with_client(config, Client::new()).await
+}
+
+/// Run the command and ping healthchecks using `client`, which may be shared
+/// between concurrent runs.
+#[tracing::instrument(skip(client))]
+pub(crate) async fn run_with_client(
+    config: Config,
+    client: Client,
+) -> Result<u8> {
+    let url = check_url(config.url.clone(), &config.check)?;
     info!("using base url: {}", url);
 
-    let client = Client::builder()
-        .timeout(Duration::from_secs(config.timeout))
-        .build()?;
+    // Identifies this invocation to healthchecks so that start and end pings
+    // from overlapping runs of the same check are paired correctly
+    let rid = Uuid::new_v4();
+    info!("using run id: {rid}");
+
+    let timeout = Duration::from_secs(config.timeout);
+    let backoff = Duration::from_millis(config.retry_backoff);
+
+    let transport =
+        Transport::new(client.clone(), timeout, config.retries, backoff);
+    let pinger = Pinger::new(transport, url, rid);
+    if config.dry_run {
+        return dry_run::print(&config, &pinger);
+    }
+
+    let started = SystemTime::now();
+
+    // Pings spooled by earlier runs go first so that they arrive in order,
+    // without retries so that an ongoing outage doesn't hold up this run
+    spool::replay(&Transport::new(client, timeout, 0, backoff)).await;
+
+    // Held until the end of the run to prevent overlapping runs of the check
+    let _lock = if let Some(policy) = config.lock {
+        let wait = Duration::from_secs(config.lock_wait);
+        match lock::acquire(&config.check, policy, wait).await? {
+            Some(lock) => Some(lock),
+            // Interrupted while waiting
+            None if signals::received().is_some() => None,
+            None => {
+                return lock::locked(&config, policy, &pinger, started).await;
+            }
+        }
+    } else {
+        None
+    };
+
+    // hc-runner was asked to exit before the command could be started
+    if let Some(signo) = signals::received() {
+        let msg = format!(
+            "hc-runner: received {}, not running command\n",
+            exec::signal_name(signo)
+        );
+        warn!("{}", msg.trim_end());
+        let code = 128 + signo;
+        let duration = started.elapsed().unwrap_or_default();
+        let record =
+            Record::new(&config, &pinger, started, duration, code, code);
+        end(&config, &pinger, record, None, msg.into(), BodyFormat::Text)
+            .await?;
+        return Ok(u8::try_from(code)?);
+    }
 
     // Some commands can be allowed to fail periodically and I only want a
     // healthchecks notification if there are zero successes in a period of
@@ -99,59 +324,70 @@ pub async fn run(config: Config) -> Result<u8> {
     let start_req = if config.success_only {
         None
     } else {
-        let client = client.clone();
-        let mut url = url.join("start")?;
+        let pinger = pinger.clone();
+        let mut url = pinger.url("start")?;
+        // Auto-provisioning is only possible for checks addressed by slug
+        if let Check::Slug(_) = config.check {
+            url.query_pairs_mut().append_pair("create", "1");
+        }
         Some(tokio::spawn(async move {
-            url.set_query(Some("create=1"));
             info!("calling start url {}", url);
-            client.head(url).send().await
+            pinger.send(Method::HEAD, url, None).await
         }))
     };
 
-    let output = if cfg!(target_os = "macos") {
-        Command::new("/usr/bin/caffeinate")
-            .args(config.command)
-            .output()?
-    } else {
-        let mut args = config.command.iter();
-        let cmd = args
-            .next()
-            .ok_or_else(|| Error::Config("command was empty".into()))?;
-        Command::new(cmd).args(args).output()?
-    };
-
-    let (stdout, stderr) = (output.stdout, output.stderr);
-    io::stdout().write_all(&stdout)?;
-    io::stderr().write_all(&stderr)?;
-
-    let status = output.status;
-    let exit_code = if status.success() {
-        0
-    } else {
-        status.code().ok_or_else(|| Error::EmptyExitCode)?
-    };
-
-    let stderr = std::str::from_utf8(&stderr)?;
-
+    let mut command = exec::command(&config)?;
+    command.env("HC_RUNNER_RID", rid.to_string());
+
+    let (progress_tx, progress) =
+        if config.log_pattern.is_some() || config.log_fd.is_some() {
+            let (tx, rx) = mpsc::channel(1024);
+            // Progress is best effort, so an outage shouldn't delay the run
+            let forward = progress::forward(
+                pinger.without_retries(),
+                rx,
+                Duration::from_secs(config.log_interval),
+                config.body_limit,
+            );
+            (Some(tx), Some(tokio::spawn(forward)))
+        } else {
+            (None, None)
+        };
+
+    let execution = exec::execute(command, &config, progress_tx).await;
+    if let Some(progress) = progress {
+        progress.await?;
+    }
     if let Some(req) = start_req {
         let _ = req.await?;
     }
 
-    match (config.success_only, exit_code) {
-        (false, _) | (true, 0) => {
-            let res = {
-                let url = url.join(exit_code.to_string().as_ref())?;
-                info!("calling end url {}", url);
-                client.post(url).body(stderr.to_string()).send().await?
-            };
-
-            if !res.status().is_success() {
-                let text = res.text().await?;
-                writeln!(io::stderr(), "failed to update status: {text}")?;
-            }
+    let execution = match execution {
+        Err(Error::Spawn { program, source }) => {
+            return spawn_failed(&config, &pinger, started, &program, &source)
+                .await;
         }
-        _ => (),
+        execution => execution?,
+    };
+    let exit_code = execution.exit_code()?;
+    let timed_out = execution.timed_out;
+
+    // Some commands use non-zero exit codes for conditions that aren't
+    // failures (e.g. `rsync` when files vanish during transfer), so these are
+    // reported to healthchecks as successes.
+    let reported_code =
+        if timed_out.is_none() && config.success_codes.contains(&exit_code) {
+            info!("treating exit code {exit_code} as success");
+            0
+        } else {
+            exit_code
+        };
+
+    finish(&config, &pinger, execution, exit_code, reported_code).await?;
+
+    if config.normalize_exit_code {
+        Ok(reported_code.try_into()?)
+    } else {
+        Ok(exit_code.try_into()?)
     }
-
-    Ok(exit_code.try_into()?)
 }
@@ -0,0 +1,156 @@
+use std::fs::{self, File};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::config::Check;
+use crate::history::Record;
+use crate::ping::Pinger;
+use crate::{BodyFormat, Config, Result, project_dirs, signals};
+
+/// Exit code returned when `--lock=fail` (or `--lock=wait` times out) finds
+/// another run of the same check in progress, `EX_TEMPFAIL` from sysexits.h
+pub const LOCKED_EXIT_CODE: u8 = 75;
+
+/// What to do when another run of the same check holds the lock
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum LockPolicy {
+    /// Exit successfully without running the command or pinging
+    Skip,
+    /// Like `skip`, but record the skipped run with a ping to `/log`
+    SkipLog,
+    /// Wait up to `--lock-wait` seconds for the lock, then `fail`
+    Wait,
+    /// Ping `/fail` and exit with code 75
+    Fail,
+}
+
+/// An exclusive lock on a check, released when dropped
+#[derive(Debug)]
+pub(crate) struct Lock(#[allow(dead_code)] File);
+
+fn lock_path(check: &Check) -> Result<PathBuf> {
+    let dirs = project_dirs()?;
+    let dir = dirs.runtime_dir().unwrap_or(dirs.cache_dir()).join("locks");
+    fs::create_dir_all(&dir)?;
+
+    let name: String = check
+        .to_string()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    Ok(dir.join(format!("{name}.lock")))
+}
+
+/// Take an exclusive lock on `file`, which is held until it is closed.
+/// Unless `block` is set, returns `false` if another process holds the lock.
+pub(crate) fn flock(file: &File, block: bool) -> io::Result<bool> {
+    let operation = if block {
+        libc::LOCK_EX
+    } else {
+        libc::LOCK_EX | libc::LOCK_NB
+    };
+    // SAFETY: `flock` has no memory safety requirements and `file` remains
+    // open for the duration of the call.
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+        Ok(false)
+    } else {
+        Err(err)
+    }
+}
+
+/// Take the lock for `check`, waiting up to `wait` if the policy is
+/// `LockPolicy::Wait`. Returns `None` if another run still holds it, or if a
+/// signal is received while waiting.
+pub(crate) async fn acquire(
+    check: &Check,
+    policy: LockPolicy,
+    wait: Duration,
+) -> Result<Option<Lock>> {
+    let path = lock_path(check)?;
+    info!("using lock file {}", path.display());
+    let file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)?;
+
+    let deadline = Instant::now()
+        + match policy {
+            LockPolicy::Wait => wait,
+            _ => Duration::ZERO,
+        };
+    loop {
+        if flock(&file, false)? {
+            return Ok(Some(Lock(file)));
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        tokio::select! {
+            () = tokio::time::sleep(Duration::from_millis(100)) => (),
+            () = signals::wait() => return Ok(None),
+        }
+    }
+}
+
+/// Handle a run that could not take the lock according to `policy`,
+/// returning the exit code.
+pub(crate) async fn locked(
+    config: &Config,
+    policy: LockPolicy,
+    pinger: &Pinger,
+    started: SystemTime,
+) -> Result<u8> {
+    let msg = "hc-runner: another run of this check is still in progress";
+    let (endpoint, code) = match policy {
+        LockPolicy::Skip => {
+            info!("{msg}, skipping");
+            (None, 0)
+        }
+        LockPolicy::SkipLog => {
+            warn!("{msg}");
+            (Some("log"), 0)
+        }
+        // Only successes are reported with `--success-only`
+        LockPolicy::Wait | LockPolicy::Fail if config.success_only => {
+            warn!("{msg}");
+            (None, LOCKED_EXIT_CODE)
+        }
+        LockPolicy::Wait | LockPolicy::Fail => {
+            warn!("{msg}");
+            (Some("fail"), LOCKED_EXIT_CODE)
+        }
+    };
+
+    let duration = started.elapsed().unwrap_or_default();
+    let record = Record::new(
+        config,
+        pinger,
+        started,
+        duration,
+        code.into(),
+        code.into(),
+    );
+    let body = format!("{msg}\n").into_bytes();
+    crate::end(config, pinger, record, endpoint, body, BodyFormat::Text)
+        .await?;
+    Ok(code)
+}
@@ -5,11 +5,12 @@ use std::process::ExitCode;
 
 use tracing_subscriber::{self, EnvFilter};
 
-use hc_runner::{Config, Error, Result, run};
+use hc_runner::redact::LogWriter;
+use hc_runner::{Action, Error, Result, batch, history, inspect, run, spool};
 
 #[tokio::main]
 async fn main() -> Result<ExitCode> {
-    let config = Config::resolve().map_err(|err| {
+    let action = Action::resolve().map_err(|err| {
         if let Error::Cli(e) = err {
             e.exit();
         } else {
@@ -23,10 +24,17 @@ async fn main() -> Result<ExitCode> {
                 .add_directive("hyper=warn".parse()?)
                 .add_directive("reqwest=warn".parse()?),
         )
-        .with_max_level(config.verbosity)
+        .with_max_level(action.verbosity())
+        .with_writer(LogWriter)
         .init();
 
-    let exit_code = run(config).await?;
+    let exit_code = match action {
+        Action::Run(config) => run(*config).await?,
+        Action::Batch(manifest) => batch::run(manifest).await?,
+        Action::Config(inspect) => inspect::run(&inspect)?,
+        Action::Flush(flush) => spool::flush(flush).await?,
+        Action::History(query) => history::show(&query)?,
+    };
     io::stdout().flush()?;
     io::stderr().flush()?;
     Ok(ExitCode::from(exit_code))
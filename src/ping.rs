@@ -0,0 +1,138 @@
+use std::time::Duration;
+
+use reqwest::{Client, Method, Response, Url};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{Result, signals};
+
+/// Sends requests to the healthchecks server, retrying those that fail for
+/// reasons that are likely to be transient.
+#[derive(Clone, Debug)]
+pub(crate) struct Transport {
+    client: Client,
+    timeout: Duration,
+    retries: u32,
+    backoff: Duration,
+}
+
+/// Sends pings for a single run of a check to the healthchecks server
+#[derive(Clone, Debug)]
+pub(crate) struct Pinger {
+    transport: Transport,
+    base: Url,
+    rid: Uuid,
+}
+
+impl Pinger {
+    pub(crate) fn new(transport: Transport, base: Url, rid: Uuid) -> Self {
+        Self {
+            transport,
+            base,
+            rid,
+        }
+    }
+
+    /// A copy that gives up on a failed ping straight away, for pings that
+    /// aren't worth holding up the run for
+    pub(crate) fn without_retries(&self) -> Self {
+        Self {
+            transport: Transport {
+                retries: 0,
+                ..self.transport.clone()
+            },
+            ..self.clone()
+        }
+    }
+
+    pub(crate) fn rid(&self) -> Uuid {
+        self.rid
+    }
+
+    /// Build the URL for `endpoint` (e.g. `start` or an exit code) relative
+    /// to the check's base URL, tagged with the run ID so that healthchecks
+    /// can pair up the start and end pings of overlapping runs.
+    pub(crate) fn url(&self, endpoint: &str) -> Result<Url> {
+        let mut url = self.base.join(endpoint)?;
+        url.query_pairs_mut()
+            .append_pair("rid", &self.rid.to_string());
+        Ok(url)
+    }
+
+    pub(crate) async fn send(
+        &self,
+        method: Method,
+        url: Url,
+        body: Option<Vec<u8>>,
+    ) -> Result<Response> {
+        self.transport.send(method, url, body).await
+    }
+}
+
+impl Transport {
+    pub(crate) fn new(
+        client: Client,
+        timeout: Duration,
+        retries: u32,
+        backoff: Duration,
+    ) -> Self {
+        Self {
+            client,
+            timeout,
+            retries,
+            backoff,
+        }
+    }
+
+    /// Send a request, retrying connection errors, timeouts, and 5xx
+    /// responses up to `retries` times with exponential backoff. 4xx
+    /// responses are returned immediately, as retrying won't help. Once
+    /// `hc-runner` has been asked to exit, one last attempt is made.
+    pub(crate) async fn send(
+        &self,
+        method: Method,
+        url: Url,
+        body: Option<Vec<u8>>,
+    ) -> Result<Response> {
+        let attempts = self.retries.saturating_add(1);
+        let mut delay = self.backoff;
+        for attempt in 1.. {
+            info!("attempt {attempt}/{attempts}: {method} {url}");
+            let mut req = self
+                .client
+                .request(method.clone(), url.clone())
+                .timeout(self.timeout);
+            if let Some(body) = &body {
+                req = req.body(body.clone());
+            }
+
+            let retry = attempt < attempts && signals::received().is_none();
+            match req.send().await {
+                Ok(res) if retry && res.status().is_server_error() => {
+                    warn!(
+                        "attempt {attempt}/{attempts}: server responded {}",
+                        res.status()
+                    );
+                }
+                Ok(res) => return Ok(res),
+                Err(e)
+                    if retry
+                        && (e.is_connect()
+                            || e.is_timeout()
+                            || e.is_request()) =>
+                {
+                    warn!("attempt {attempt}/{attempts}: {e}");
+                }
+                Err(e) => return Err(e.into()),
+            }
+
+            info!("retrying in {delay:?}");
+            tokio::select! {
+                () = tokio::time::sleep(delay) => (),
+                () = signals::wait() => info!("retrying now before exiting"),
+            }
+            delay = delay.saturating_mul(2);
+        }
+        unreachable!("loop only exits by returning")
+    }
+}
@@ -0,0 +1,144 @@
+use std::io::{self, PipeReader};
+use std::time::Duration;
+
+use regex::Regex;
+use reqwest::Method;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::unix::pipe;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+use crate::capture::{Capture, Truncation};
+use crate::ping::Pinger;
+
+/// Lines longer than this are forwarded in pieces
+const MAX_LINE: usize = 64 * 1024;
+
+/// Splits a stream of output into lines, forwarding those that match
+/// `pattern` to be sent to the `/log` endpoint.
+#[derive(Debug)]
+pub(crate) struct LineMatcher {
+    pattern: Regex,
+    tx: Sender<String>,
+    partial: Vec<u8>,
+}
+
+impl LineMatcher {
+    pub(crate) fn new(pattern: Regex, tx: Sender<String>) -> Self {
+        Self {
+            pattern,
+            tx,
+            partial: Vec::new(),
+        }
+    }
+
+    pub(crate) fn push(&mut self, data: &[u8]) {
+        self.partial.extend_from_slice(data);
+        while let Some(idx) = self.partial.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=idx).collect();
+            self.forward(&line[..idx]);
+        }
+        if self.partial.len() > MAX_LINE {
+            let line = std::mem::take(&mut self.partial);
+            self.forward(&line);
+        }
+    }
+
+    /// Forward any trailing output that wasn't terminated by a newline
+    pub(crate) fn finish(&mut self) {
+        if !self.partial.is_empty() {
+            let line = std::mem::take(&mut self.partial);
+            self.forward(&line);
+        }
+    }
+
+    fn forward(&self, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        if self.pattern.is_match(&line)
+            && self.tx.try_send(line.into_owned()).is_err()
+        {
+            debug!("progress queue full, dropping line");
+        }
+    }
+}
+
+/// Forward every line written by the command to the `--log-fd` pipe,
+/// dropping lines rather than slowing down the command if healthchecks can't
+/// keep up.
+pub(crate) async fn read_fd(
+    reader: PipeReader,
+    tx: Sender<String>,
+) -> io::Result<()> {
+    let mut reader =
+        BufReader::new(pipe::Receiver::from_owned_fd(reader.into())?);
+    let mut line = Vec::new();
+    while reader.read_until(b'\n', &mut line).await? > 0 {
+        if line.last() == Some(&b'\n') {
+            line.pop();
+        }
+        let text = String::from_utf8_lossy(&line).into_owned();
+        if tx.try_send(text).is_err() {
+            debug!("progress queue full, dropping line");
+        }
+        line.clear();
+    }
+    Ok(())
+}
+
+/// Post progress lines to the `/log` endpoint as they arrive, sending at
+/// most one ping per `interval`; lines that arrive in the meantime are
+/// batched into the next ping. Returns once all senders have been dropped
+/// and any remaining lines have been sent.
+pub(crate) async fn forward(
+    pinger: Pinger,
+    mut rx: Receiver<String>,
+    interval: Duration,
+    limit: usize,
+) {
+    let new_batch = || Capture::new(limit, Truncation::Tail);
+    let mut batch = new_batch();
+    let mut pending = false;
+    let mut next_send = Instant::now();
+
+    loop {
+        tokio::select! {
+            line = rx.recv() => match line {
+                Some(line) => {
+                    batch.push(line.as_bytes());
+                    batch.push(b"\n");
+                    pending = true;
+                }
+                None => break,
+            },
+            () = tokio::time::sleep_until(next_send), if pending => {
+                let body = std::mem::replace(&mut batch, new_batch());
+                send(&pinger, body.into_body()).await;
+                pending = false;
+                next_send = Instant::now() + interval;
+            }
+        }
+    }
+
+    if pending {
+        send(&pinger, batch.into_body()).await;
+    }
+}
+
+async fn send(pinger: &Pinger, body: Vec<u8>) {
+    let url = match pinger.url("log") {
+        Ok(url) => url,
+        Err(e) => {
+            warn!("unable to build log url: {e}");
+            return;
+        }
+    };
+    info!("calling log url {}", url);
+    match pinger.send(Method::POST, url, Some(body)).await {
+        Ok(res) if !res.status().is_success() => {
+            warn!("failed to send progress: {}", res.status());
+        }
+        Ok(_) => (),
+        Err(e) => warn!("failed to send progress: {e}"),
+    }
+}
@@ -0,0 +1,133 @@
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{LazyLock, RwLock};
+
+use regex::Regex;
+use reqwest::Url;
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::config::Check;
+
+/// Replaces secrets in redacted text
+const MASK: &str = "********";
+
+/// Ping keys and check UUIDs in use by this process
+static SECRETS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+/// Set by `--show-secrets`
+static SHOW: AtomicBool = AtomicBool::new(false);
+
+/// A UUID used as a URL path segment, i.e. a check UUID (as opposed to the
+/// run ID, which is only sent as a query parameter)
+static UUID_SEGMENT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i)/[0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12}\b",
+    )
+    .expect("valid regex")
+});
+
+/// The ping key in a base URL, i.e. the last segment of its path
+fn ping_key(url: &Url) -> Option<String> {
+    url.path_segments()
+        .and_then(|mut segments| segments.rfind(|s| !s.is_empty()))
+        .map(ToString::to_string)
+}
+
+/// The secrets that allow pinging the check: the ping key for a check
+/// addressed by slug, or the check's UUID.
+fn secrets(url: &Url, check: &Check) -> Vec<String> {
+    match check {
+        Check::Slug(_) => ping_key(url).into_iter().collect(),
+        Check::Uuid(uuid) => vec![uuid.to_string()],
+    }
+}
+
+fn add(secrets: impl IntoIterator<Item = String>) {
+    let mut registered = SECRETS
+        .write()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    for secret in secrets {
+        if !secret.is_empty() && !registered.contains(&secret) {
+            registered.push(secret);
+        }
+    }
+}
+
+/// Mask the secrets for pinging `check` wherever they appear from now on.
+pub(crate) fn register(url: &Url, check: &Check) {
+    add(secrets(url, check));
+}
+
+/// Mask the ping key in `url` wherever it appears from now on, for when
+/// there is no check to tell whether `url` is used with one.
+pub(crate) fn register_ping_key(url: &Url) {
+    add(ping_key(url));
+}
+
+/// Stop masking secrets, for `--show-secrets`.
+pub(crate) fn show() {
+    SHOW.store(true, Ordering::Relaxed);
+}
+
+/// Mask `secrets` and any check UUIDs in URLs in `text`.
+fn redact_with<'a>(text: &'a str, secrets: &[String]) -> Cow<'a, str> {
+    let mut text = UUID_SEGMENT.replace_all(text, format!("/{MASK}"));
+    for secret in secrets {
+        if text.contains(secret.as_str()) {
+            text = text.replace(secret.as_str(), MASK).into();
+        }
+    }
+    text
+}
+
+/// Mask the registered secrets and any check UUIDs in URLs in `text`, unless
+/// `--show-secrets` was given.
+pub(crate) fn redact(text: &str) -> Cow<'_, str> {
+    if SHOW.load(Ordering::Relaxed) {
+        return text.into();
+    }
+    let secrets = SECRETS
+        .read()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    redact_with(text, &secrets)
+}
+
+/// Writes log lines to stdout with secrets redacted
+#[derive(Debug, Default)]
+pub struct LogWriter;
+
+impl<'a> MakeWriter<'a> for LogWriter {
+    type Writer = LogLine;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        LogLine(Vec::new())
+    }
+}
+
+/// A single log event, buffered so that secrets split across writes are
+/// still redacted, and written to stdout when dropped
+#[derive(Debug)]
+pub struct LogLine(Vec<u8>);
+
+impl Write for LogLine {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for LogLine {
+    fn drop(&mut self) {
+        let line = String::from_utf8_lossy(&self.0);
+        // Nowhere left to report a failure to log
+        let _ = io::stdout().lock().write_all(redact(&line).as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests;
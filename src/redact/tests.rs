@@ -0,0 +1,42 @@
+use uuid::Uuid;
+
+use super::*;
+
+#[test]
+fn test_secrets() {
+    let url = Url::parse("https://hc-ping.com/abc123/").unwrap();
+    assert_eq!(secrets(&url, &Check::Slug("foo".into())), ["abc123"]);
+
+    let uuid = Uuid::new_v4();
+    let url = Url::parse("https://hc-ping.com/").unwrap();
+    assert_eq!(secrets(&url, &Check::Uuid(uuid)), [uuid.to_string()]);
+    assert!(secrets(&url, &Check::Slug("foo".into())).is_empty());
+}
+
+#[test]
+fn test_redact_with() {
+    let secrets = ["abc123".to_string()];
+    assert_eq!(
+        redact_with("https://hc-ping.com/abc123/foo/start", &secrets),
+        "https://hc-ping.com/********/foo/start"
+    );
+
+    // Check UUIDs are masked even if they weren't registered, but run IDs
+    // are left alone
+    let uuid = "0B2A9E8C-1D6B-4A5E-9A57-3F4E2B1C0D9E";
+    let rid = Uuid::new_v4();
+    let text = format!(
+        "error sending request for https://hc-ping.com/{uuid}/0?rid={rid}"
+    );
+    assert_eq!(
+        redact_with(&text, &[]),
+        format!(
+            "error sending request for https://hc-ping.com/********/0?rid={rid}"
+        )
+    );
+
+    assert!(matches!(
+        redact_with("nothing to see", &secrets),
+        Cow::Borrowed(_)
+    ));
+}
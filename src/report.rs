@@ -0,0 +1,93 @@
+use std::env;
+use std::ffi::CStr;
+use std::io::Write;
+use std::os::unix::process::ExitStatusExt;
+
+use humantime::format_rfc3339_millis;
+use serde::Serialize;
+
+use crate::exec::{self, Execution, Output};
+use crate::{Config, Result};
+
+/// The end ping body for `--body-format=json`
+#[derive(Debug, Serialize)]
+struct Report<'a> {
+    hc_runner_version: &'static str,
+    hostname: String,
+    user: String,
+    command: &'a [String],
+    start: String,
+    end: String,
+    duration_secs: f64,
+    exit_code: i32,
+    signal: Option<String>,
+    timed_out: bool,
+    /// Peak resident set size in bytes
+    max_rss: u64,
+    user_cpu_secs: f64,
+    system_cpu_secs: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stdout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stderr: Option<String>,
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: `buf` is valid for writes of its length
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return "unknown".into();
+    }
+    CStr::from_bytes_until_nul(&buf)
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+fn user() -> String {
+    env::var("USER")
+        .or_else(|_| env::var("LOGNAME"))
+        .unwrap_or_else(|_| {
+            // SAFETY: `getuid` is always successful
+            unsafe { libc::getuid() }.to_string()
+        })
+}
+
+/// Build the body of the end ping for `execution` according to
+/// `--body-format`.
+pub(crate) fn body(
+    config: &Config,
+    execution: Execution,
+    exit_code: i32,
+) -> Result<Vec<u8>> {
+    let note = execution.note();
+    let (stdout, stderr) = match execution.output {
+        Output::Combined(mut body) => {
+            if let Some(note) = note {
+                writeln!(body, "{note}")?;
+            }
+            return Ok(body);
+        }
+        Output::Separate { stdout, stderr } => (stdout, stderr),
+    };
+    let lossy = |output: Vec<u8>| String::from_utf8_lossy(&output).into();
+
+    let report = Report {
+        hc_runner_version: env!("CARGO_PKG_VERSION"),
+        hostname: hostname(),
+        user: user(),
+        command: &config.command,
+        start: format_rfc3339_millis(execution.started).to_string(),
+        end: format_rfc3339_millis(execution.started + execution.duration)
+            .to_string(),
+        duration_secs: execution.duration.as_secs_f64(),
+        exit_code,
+        signal: execution.status.signal().map(exec::signal_name),
+        timed_out: execution.timed_out.is_some(),
+        max_rss: execution.usage.max_rss,
+        user_cpu_secs: execution.usage.user_time.as_secs_f64(),
+        system_cpu_secs: execution.usage.system_time.as_secs_f64(),
+        stdout: stdout.map(lossy),
+        stderr: stderr.map(lossy),
+    };
+    Ok(serde_json::to_vec_pretty(&report)?)
+}
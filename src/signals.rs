@@ -0,0 +1,53 @@
+use std::io;
+use std::sync::LazyLock;
+
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::watch;
+
+/// Signals asking `hc-runner` to exit, which are passed on to the command
+const FORWARDED_SIGNALS: [libc::c_int; 3] =
+    [libc::SIGHUP, libc::SIGINT, libc::SIGTERM];
+
+/// The last of `FORWARDED_SIGNALS` to be received, or 0 if none has been
+static RECEIVED: LazyLock<watch::Sender<libc::c_int>> =
+    LazyLock::new(|| watch::channel(0).0);
+
+/// Record `FORWARDED_SIGNALS` instead of letting them kill `hc-runner`, so
+/// that they can be passed on to the command and the run still reported.
+/// Once a signal has been received, no new commands are started and pings
+/// are no longer retried.
+pub(crate) fn listen() -> io::Result<()> {
+    for signo in FORWARDED_SIGNALS {
+        let mut signals = signal(SignalKind::from_raw(signo))?;
+        tokio::spawn(async move {
+            while signals.recv().await.is_some() {
+                RECEIVED.send_replace(signo);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// The last signal received, if any
+pub(crate) fn received() -> Option<libc::c_int> {
+    match *RECEIVED.borrow() {
+        0 => None,
+        signo => Some(signo),
+    }
+}
+
+/// Notified of each signal received from now on, as well as the last one
+/// already received (if any)
+pub(crate) fn subscribe() -> watch::Receiver<libc::c_int> {
+    let mut rx = RECEIVED.subscribe();
+    if *rx.borrow() != 0 {
+        rx.mark_changed();
+    }
+    rx
+}
+
+/// Wait until a signal has been received.
+pub(crate) async fn wait() {
+    // The sender is never dropped
+    let _ = subscribe().wait_for(|&signo| signo != 0).await;
+}
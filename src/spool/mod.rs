@@ -0,0 +1,270 @@
+use std::collections::HashSet;
+use std::fs::{self, DirBuilder, File};
+use std::io::{self, Write};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use humantime::format_rfc3339_millis;
+use reqwest::{Client, Method, Response, Url};
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use tracing::{Level, info, warn};
+use uuid::Uuid;
+
+use crate::config::Check;
+use crate::lock::flock;
+use crate::ping::{Pinger, Transport};
+use crate::{BodyFormat, Error, Result, project_dirs, redact};
+
+/// Options for `hc-runner flush`
+#[derive(Debug)]
+pub struct Flush {
+    pub(crate) timeout: u64,
+    pub(crate) retries: u32,
+    pub(crate) retry_backoff: u64,
+    pub verbosity: Level,
+}
+
+/// A ping that could not be delivered, stored as a JSON file in the spool
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct Spooled {
+    url: Url,
+    /// The check's slug, if it is addressed by one, in which case the ping
+    /// key precedes it in `url`
+    #[serde(default)]
+    slug: Option<String>,
+    created: String,
+    body: String,
+}
+
+impl Spooled {
+    /// Mask the secrets in `url` from now on, as for the run that spooled it.
+    fn register_secrets(&self) {
+        let Some(slug) = &self.slug else {
+            // Check UUIDs are always masked
+            return;
+        };
+        // Drop the slug and endpoint to get the base URL
+        if let Ok(base) = self.url.join("..") {
+            redact::register(&base, &Check::Slug(slug.clone()));
+        }
+    }
+}
+
+fn dir() -> Result<PathBuf> {
+    Ok(project_dirs()?.data_dir().join("spool"))
+}
+
+/// Write a ping to `dir`, named so that spooled pings sort in the order they
+/// were created.
+fn save_in(
+    dir: &Path,
+    url: &Url,
+    check: &Check,
+    mut body: Vec<u8>,
+    format: BodyFormat,
+) -> Result<PathBuf> {
+    // The URLs contain the ping key
+    DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+
+    let now = SystemTime::now();
+    let created = format_rfc3339_millis(now).to_string();
+    // The JSON body already includes when the command ran
+    if format == BodyFormat::Text {
+        writeln!(
+            body,
+            "hc-runner: ping spooled at {created} after failing to reach \
+            healthchecks"
+        )?;
+    }
+    let spooled = Spooled {
+        url: url.clone(),
+        slug: match check {
+            Check::Slug(slug) => Some(slug.clone()),
+            Check::Uuid(_) => None,
+        },
+        created,
+        body: String::from_utf8_lossy(&body).into(),
+    };
+
+    let nanos = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let path =
+        dir.join(format!("{:020}-{}.json", nanos.as_nanos(), Uuid::new_v4()));
+    let mut tmp = NamedTempFile::new_in(dir)?;
+    serde_json::to_writer(&mut tmp, &spooled)?;
+    tmp.persist(&path).map_err(|e| e.error)?;
+    Ok(path)
+}
+
+/// What became of a ping sent with `post`
+#[derive(Debug)]
+pub(crate) enum Posted {
+    /// The server responded, though it may have rejected the ping
+    Delivered(Response),
+    /// The ping will be replayed later, having failed for the given reason
+    Spooled(String),
+}
+
+/// POST `body` to `url`, spooling it to be replayed later if the server
+/// can't be reached (or responds with a server error). Only returns an error
+/// if the ping could neither be sent nor spooled.
+pub(crate) async fn post(
+    pinger: &Pinger,
+    check: &Check,
+    url: Url,
+    body: Vec<u8>,
+    format: BodyFormat,
+) -> Result<Posted> {
+    let res = pinger
+        .send(Method::POST, url.clone(), Some(body.clone()))
+        .await;
+    let reason = match &res {
+        Ok(res) if !res.status().is_server_error() => None,
+        Ok(res) => Some(format!("server responded {}", res.status())),
+        Err(e) => Some(e.to_string()),
+    };
+    let Some(reason) = reason else {
+        return res.map(Posted::Delivered);
+    };
+    match dir().and_then(|dir| save_in(&dir, &url, check, body, format)) {
+        Ok(path) => {
+            warn!(
+                "ping not delivered ({reason}), spooled to {}",
+                path.display()
+            );
+            Ok(Posted::Spooled(reason))
+        }
+        Err(e) => {
+            warn!("failed to spool ping: {e}");
+            res.map(Posted::Delivered)
+        }
+    }
+}
+
+/// Spooled pings in `dir`, oldest first
+fn pending(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut paths = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Send the pings spooled in `dir` in the order they were created, removing
+/// each once it has been delivered. Once a ping to a server fails, later
+/// pings to that server are left in the spool so that they stay in order.
+///
+/// Returns the number of pings sent and the number remaining.
+async fn replay_from(
+    dir: &Path,
+    transport: &Transport,
+) -> Result<(usize, usize)> {
+    let paths = pending(dir)?;
+    if paths.is_empty() {
+        return Ok((0, 0));
+    }
+
+    // Another run is already replaying the spool
+    let lock = File::create(dir.join(".lock"))?;
+    if !flock(&lock, false)? {
+        info!("spool is locked, skipping replay");
+        return Ok((0, paths.len()));
+    }
+
+    let mut unreachable = HashSet::new();
+    let (mut sent, mut remaining) = (0, 0);
+    for path in paths {
+        let spooled: Spooled = match fs::read(&path)
+            .map_err(Error::from)
+            .and_then(|data| Ok(serde_json::from_slice(&data)?))
+        {
+            Ok(spooled) => spooled,
+            Err(e) => {
+                warn!("skipping unreadable {}: {e}", path.display());
+                remaining += 1;
+                continue;
+            }
+        };
+        spooled.register_secrets();
+        let origin = spooled.url.origin();
+        if unreachable.contains(&origin) {
+            remaining += 1;
+            continue;
+        }
+
+        info!("replaying ping spooled at {}", spooled.created);
+        let res = transport
+            .send(Method::POST, spooled.url, Some(spooled.body.into()))
+            .await;
+        match res {
+            // Expected while healthchecks is unreachable, so not a warning
+            Ok(res) if res.status().is_server_error() => {
+                info!("server responded {}", res.status());
+            }
+            Err(e) => info!("{e}"),
+            Ok(res) => {
+                if !res.status().is_success() {
+                    warn!(
+                        "dropping spooled ping rejected by server: {}",
+                        res.text().await?
+                    );
+                }
+                fs::remove_file(&path)?;
+                sent += 1;
+                continue;
+            }
+        }
+        unreachable.insert(origin);
+        remaining += 1;
+    }
+    Ok((sent, remaining))
+}
+
+/// Replay pings spooled by earlier runs, logging rather than returning any
+/// errors so that they don't affect the current run.
+pub(crate) async fn replay(transport: &Transport) {
+    match dir() {
+        Ok(dir) => match replay_from(&dir, transport).await {
+            Ok((0, 0)) => (),
+            Ok((sent, remaining)) => {
+                info!("replayed {sent} spooled pings, {remaining} remaining");
+            }
+            Err(e) => warn!("failed to replay spooled pings: {e}"),
+        },
+        Err(e) => warn!("failed to replay spooled pings: {e}"),
+    }
+}
+
+/// Replay all spooled pings.
+///
+/// # Errors
+/// Returns `1` if any pings could not be delivered, otherwise `0`
+pub async fn flush(flush: Flush) -> Result<u8> {
+    let transport = Transport::new(
+        Client::new(),
+        Duration::from_secs(flush.timeout),
+        flush.retries,
+        Duration::from_millis(flush.retry_backoff),
+    );
+    let (sent, remaining) = replay_from(&dir()?, &transport).await?;
+    writeln!(
+        io::stdout(),
+        "sent {sent} spooled pings, {remaining} remaining"
+    )?;
+    Ok(u8::from(remaining > 0))
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,91 @@
+use super::*;
+
+use httpmock::prelude::*;
+
+fn transport() -> Transport {
+    Transport::new(Client::new(), Duration::from_secs(5), 0, Duration::ZERO)
+}
+
+fn slug() -> Check {
+    Check::Slug("slug".into())
+}
+
+#[test]
+fn test_save_in_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let url: Url = "http://localhost/key/slug/0".parse().unwrap();
+    for body in ["first", "second", "third"] {
+        save_in(dir.path(), &url, &slug(), body.into(), BodyFormat::Json)
+            .unwrap();
+    }
+
+    let bodies: Vec<_> = pending(dir.path())
+        .unwrap()
+        .into_iter()
+        .map(|path| {
+            let spooled: Spooled =
+                serde_json::from_slice(&fs::read(path).unwrap()).unwrap();
+            spooled.body
+        })
+        .collect();
+    assert_eq!(bodies, ["first", "second", "third"]);
+}
+
+#[test]
+fn test_save_annotates_text() {
+    let dir = tempfile::tempdir().unwrap();
+    let url: Url = "http://localhost/key/slug/0".parse().unwrap();
+    let path = save_in(
+        dir.path(),
+        &url,
+        &slug(),
+        "output\n".into(),
+        BodyFormat::Text,
+    )
+    .unwrap();
+    let spooled: Spooled =
+        serde_json::from_slice(&fs::read(path).unwrap()).unwrap();
+    assert!(
+        spooled
+            .body
+            .starts_with("output\nhc-runner: ping spooled at ")
+    );
+    assert_eq!(spooled.url, url);
+    assert_eq!(spooled.slug.as_deref(), Some("slug"));
+}
+
+#[tokio::test]
+async fn test_replay() {
+    let up = MockServer::start_async().await;
+    let mock = up
+        .mock_async(|when, then| {
+            when.method(POST).path("/slug/0");
+            then.status(200);
+        })
+        .await;
+    let down = MockServer::start_async().await;
+    let mock_down = down
+        .mock_async(|when, then| {
+            when.method(POST);
+            then.status(503);
+        })
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let up_url: Url = up.url("/slug/0").parse().unwrap();
+    let down_url: Url = down.url("/slug/0").parse().unwrap();
+    for url in [&down_url, &up_url, &down_url, &up_url] {
+        save_in(dir.path(), url, &slug(), Vec::new(), BodyFormat::Text)
+            .unwrap();
+    }
+
+    // Only the first ping to the server that is down is attempted
+    let (sent, remaining) =
+        replay_from(dir.path(), &transport()).await.unwrap();
+    assert_eq!((sent, remaining), (2, 2));
+    mock.assert_calls_async(2).await;
+    mock_down.assert_calls_async(1).await;
+    assert_eq!(pending(dir.path()).unwrap().len(), 2);
+
+    assert_eq!(replay_from(dir.path(), &transport()).await.unwrap(), (0, 2));
+}
@@ -1,4 +1,9 @@
-use std::sync::{LazyLock, Mutex};
+use std::ffi::CStr;
+use std::io::{self, Write};
+use std::os::fd::FromRawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
 use std::{env, fs, process, str};
 
 use httpmock::prelude::*;
@@ -7,9 +12,64 @@ use tempfile::{Builder, tempdir};
 
 const EXE: &str = env!("CARGO_BIN_EXE_hc-runner");
 
-static ENV_LOCK: LazyLock<Mutex<()>> = LazyLock::new(Mutex::default);
+/// A temporary directory for a test's config, history, spool, and lock
+/// files, keeping them out of my actual filesystem. Removed when dropped.
+struct Sandbox(tempfile::TempDir);
 
-fn setup_server(ignore: bool) -> httpmock::MockServer {
+impl Sandbox {
+    fn new() -> Self {
+        Self(tempdir().unwrap())
+    }
+
+    fn home(&self) -> PathBuf {
+        self.0.path().join("home")
+    }
+
+    fn data_dir(&self) -> PathBuf {
+        self.0.path().join("data")
+    }
+
+    /// Write the user's config file
+    fn write_config(&self, contents: impl AsRef<str>) {
+        let suffix = if cfg!(target_os = "macos") {
+            "Library/Application Support/com.n8henrie.hc-runner/config.toml"
+        } else if cfg!(target_os = "linux") {
+            ".config/hc-runner/config.toml"
+        } else {
+            panic!(
+                "Testing not (yet) supported on your platform. Contributions appreciated!"
+            );
+        };
+
+        let path = self.home().join(suffix);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents.as_ref()).unwrap();
+    }
+
+    /// A command running `hc-runner` with its files in the sandbox
+    fn command(&self) -> process::Command {
+        let mut command = process::Command::new(EXE);
+        // Settings from my actual environment would affect the results
+        for (key, _) in env::vars_os() {
+            if key.to_string_lossy().starts_with("HC_RUNNER_") {
+                command.env_remove(key);
+            }
+        }
+        command
+            .env("HOME", self.home())
+            .env_remove("XDG_CONFIG_HOME")
+            .env_remove("XDG_CACHE_HOME")
+            .env("XDG_CONFIG_DIRS", self.0.path().join("config"))
+            .env("XDG_DATA_HOME", self.data_dir())
+            .env("XDG_RUNTIME_DIR", self.0.path().join("runtime"))
+            // Tests that use a broken URL would otherwise spend several
+            // seconds retrying
+            .env("HC_RUNNER_RETRIES", "0");
+        command
+    }
+}
+
+fn setup_server(ignore: bool) -> (httpmock::MockServer, Sandbox) {
     let server: httpmock::MockServer = httpmock::MockServer::start();
 
     if ignore {
@@ -19,20 +79,14 @@ fn setup_server(ignore: bool) -> httpmock::MockServer {
         });
     }
 
-    // Prevent reading config from my actual filesystem.
-    // Unsetting `HOME` doesn't work due to this `fallback`:
-    // https://github.com/dirs-dev/dirs-sys-rs/blob/8bcd4aa2c35990d57a2cff2953793525fc42709c/src/lib.rs#L44
-    unsafe {
-        env::set_var("HOME", "/dev/null");
-    };
-
-    server
+    (server, Sandbox::new())
 }
 
 #[test]
 fn catches_stdout() {
-    let server = setup_server(true);
-    let result = process::Command::new(EXE)
+    let (server, sandbox) = setup_server(true);
+    let result = sandbox
+        .command()
         .args([
             "--slug=_",
             "--url",
@@ -50,8 +104,9 @@ fn catches_stdout() {
 
 #[test]
 fn catches_stderr() {
-    let server = setup_server(true);
-    let result = process::Command::new(EXE)
+    let (server, sandbox) = setup_server(true);
+    let result = sandbox
+        .command()
         .args(["--slug=_", "--url", &server.url(""), "grep", "foo", "bar"])
         .output()
         .unwrap();
@@ -88,8 +143,9 @@ fn successful_run<'a>(
 
 #[test]
 fn catches_stdout_and_stderr() {
-    let server = setup_server(true);
-    let result = process::Command::new(EXE)
+    let (server, sandbox) = setup_server(true);
+    let result = sandbox
+        .command()
         .args([
             "--slug=_",
             "--url",
@@ -116,8 +172,9 @@ fn catches_stdout_and_stderr() {
 
 #[test]
 fn propagates_success() {
-    let server = setup_server(true);
-    let status = process::Command::new(EXE)
+    let (server, sandbox) = setup_server(true);
+    let status = sandbox
+        .command()
         .args(["--slug=_", "--url", &server.url(""), "true"])
         .output()
         .unwrap()
@@ -127,8 +184,9 @@ fn propagates_success() {
 
 #[test]
 fn propagates_error() {
-    let server = setup_server(true);
-    let status = process::Command::new(EXE)
+    let (server, sandbox) = setup_server(true);
+    let status = sandbox
+        .command()
         .args(["--slug=_", "--url", &server.url(""), "false"])
         .output()
         .unwrap()
@@ -138,10 +196,11 @@ fn propagates_error() {
 
 #[test]
 fn calls_server_success() {
-    let server = setup_server(false);
+    let (server, sandbox) = setup_server(false);
     let (mock_start, mock_end) = successful_run(&server, "winner");
 
-    let status = process::Command::new(EXE)
+    let status = sandbox
+        .command()
         .args(["--slug=winner", "--url", &server.url(""), "echo", "hooray!"])
         .output()
         .unwrap()
@@ -153,7 +212,7 @@ fn calls_server_success() {
 
 #[test]
 fn calls_server_error() {
-    let server = setup_server(false);
+    let (server, sandbox) = setup_server(false);
 
     let mock_start = server.mock(|when, then| {
         when.method(HEAD)
@@ -166,7 +225,8 @@ fn calls_server_error() {
         then.status(200);
     });
 
-    let status = process::Command::new(EXE)
+    let status = sandbox
+        .command()
         .args([
             "--slug=failer",
             "--url",
@@ -183,52 +243,22 @@ fn calls_server_error() {
     assert!(!status.success());
 }
 
-/// Returns the `TempDir` to prevent destruction at the end of the function
-fn temp_config(contents: impl AsRef<str>) -> tempfile::TempDir {
-    let home = tempdir().unwrap();
-    unsafe {
-        env::set_var("HOME", home.path());
-    }
-    unsafe {
-        env::remove_var("XDG_CONFIG_HOME");
-    }
-    let suffix = if cfg!(target_os = "macos") {
-        "Library/Application Support/com.n8henrie.hc-runner/config.toml"
-    } else if cfg!(target_os = "linux") {
-        ".config/hc-runner/config.toml"
-    } else {
-        panic!(
-            "Testing not (yet) supported on your platform. Contributions appreciated!"
-        );
-    };
-
-    let path = home.path().to_path_buf().join(suffix);
-    fs::create_dir_all(path.parent().unwrap()).unwrap();
-    fs::write(path, contents.as_ref()).unwrap();
-    home
-}
-
 #[test]
 fn file_config_works() {
-    let server = setup_server(false);
+    let (server, sandbox) = setup_server(false);
     let (mock_start, mock_end) = successful_run(&server, "winner");
 
-    let mut cmd = process::Command::new(EXE);
+    let mut cmd = sandbox.command();
     let cmd = cmd.args(["--slug=winner", "echo", "hooray!"]);
 
-    let env_guard = ENV_LOCK.lock().unwrap();
-    unsafe {
-        env::remove_var("HC_RUNNER_URL");
-    }
     let status = cmd.output().unwrap().status;
     mock_start.assert_calls(0);
     mock_end.assert_calls(0);
     assert!(!status.success());
 
     // Should work again obtaining the URL from the config file
-    let _tmp = temp_config(format!(r#"url = "{}""#, server.url("")));
+    sandbox.write_config(format!(r#"url = "{}""#, server.url("")));
     let status = cmd.output().unwrap().status;
-    drop(env_guard);
 
     mock_start.assert_calls(1);
     mock_end.assert_calls(1);
@@ -237,28 +267,19 @@ fn file_config_works() {
 
 #[test]
 fn env_works() {
-    let server = setup_server(false);
+    let (server, sandbox) = setup_server(false);
     let (mock_start, mock_end) = successful_run(&server, "winner");
 
-    let mut cmd = process::Command::new(EXE);
+    let mut cmd = sandbox.command();
     let cmd = cmd.args(["--slug=winner", "echo", "hooray!"]);
 
-    let env_guard = ENV_LOCK.lock().unwrap();
-
-    // Ensure this is not set, verify failure in absence
-    unsafe {
-        env::remove_var("HC_RUNNER_URL");
-    }
+    // Verify failure in absence
     let status = cmd.output().unwrap().status;
     mock_start.assert_calls(0);
     mock_end.assert_calls(0);
     assert!(!status.success());
 
-    unsafe {
-        env::set_var("HC_RUNNER_URL", server.url(""));
-    }
-    let status = cmd.output().unwrap().status;
-    drop(env_guard);
+    let status = cmd.env("HC_RUNNER_URL", server.url("")).status().unwrap();
 
     mock_start.assert_calls(1);
     mock_end.assert_calls(1);
@@ -268,31 +289,27 @@ fn env_works() {
 // This tests that the `--url` flag overrides the envvar default.
 #[test]
 fn flag_overrides_env() {
-    let server = setup_server(false);
+    let (server, sandbox) = setup_server(false);
     let (mock_start, mock_end) = successful_run(&server, "winner");
 
     let mut args = vec!["--slug=winner", "echo", "hooray!"];
     let cmd = |args| {
-        process::Command::new(EXE)
+        sandbox
+            .command()
+            .env("HC_RUNNER_URL", "http://broken")
             .args(args)
             .output()
             .unwrap()
-            .status
     };
 
-    let env_guard = ENV_LOCK.lock().unwrap();
-    unsafe {
-        env::set_var("HC_RUNNER_URL", "http://broken");
-    }
-    let status = cmd(args.clone());
+    let output = cmd(args.clone());
     mock_start.assert_calls(0);
     mock_end.assert_calls(0);
-    assert!(!status.success());
+    assert!(str::from_utf8(&output.stdout).unwrap().contains("spooled"));
 
     let url_flag = format!("--url={}", server.url(""));
     args.insert(0, url_flag.as_ref());
-    let status = cmd(args);
-    drop(env_guard);
+    let status = cmd(args).status;
 
     mock_start.assert_calls(1);
     mock_end.assert_calls(1);
@@ -301,29 +318,21 @@ fn flag_overrides_env() {
 
 #[test]
 fn env_overrides_file() {
-    let server = setup_server(false);
+    let (server, sandbox) = setup_server(false);
     let (mock_start, mock_end) = successful_run(&server, "winner");
 
-    let mut cmd = process::Command::new(EXE);
+    let mut cmd = sandbox.command();
     let cmd = cmd.args(["--slug=winner", "echo", "hooray!"]);
 
-    let env_guard = ENV_LOCK.lock().unwrap();
-    unsafe {
-        env::remove_var("HC_RUNNER_URL");
-    }
     // Set a broken url in the config file, failure shows it was used
-    let _tmp = temp_config(r#"url = "http://broken""#);
-    let status = cmd.output().unwrap().status;
+    sandbox.write_config(r#"url = "http://broken""#);
+    let output = cmd.output().unwrap();
     mock_start.assert_calls(0);
     mock_end.assert_calls(0);
-    assert!(!status.success());
+    assert!(str::from_utf8(&output.stdout).unwrap().contains("spooled"));
 
     // Confirm settings the envvar overrides the bad config
-    unsafe {
-        env::set_var("HC_RUNNER_URL", server.url(""));
-    }
-    let status = cmd.output().unwrap().status;
-    drop(env_guard);
+    let status = cmd.env("HC_RUNNER_URL", server.url("")).status().unwrap();
 
     mock_start.assert_calls(1);
     mock_end.assert_calls(1);
@@ -332,14 +341,11 @@ fn env_overrides_file() {
 
 #[test]
 fn specify_config_file() {
-    let server = setup_server(false);
+    let (server, sandbox) = setup_server(false);
     let (mock_start, mock_end) = successful_run(&server, "winner");
 
-    let env_guard = ENV_LOCK.lock().unwrap();
-    unsafe {
-        env::remove_var("HC_RUNNER_URL");
-    }
-    let status = process::Command::new(EXE)
+    let status = sandbox
+        .command()
         .args(["--slug=winner", "echo", "hooray!"])
         .output()
         .unwrap()
@@ -353,7 +359,8 @@ fn specify_config_file() {
     fs::write(config.path(), format!(r#"url = "{}""#, server.url("")))
         .unwrap();
 
-    let status = process::Command::new(EXE)
+    let status = sandbox
+        .command()
         .args([
             "--config",
             config.path().to_str().unwrap(),
@@ -364,8 +371,1150 @@ fn specify_config_file() {
         .output()
         .unwrap()
         .status;
-    drop(env_guard);
     mock_start.assert_calls(1);
     mock_end.assert_calls(1);
     assert!(status.success());
+
+    let url_flag = format!("--url={}", server.url(""));
+    let status = sandbox
+        .command()
+        .args(["--config=/dev/null", &url_flag, "--slug=winner", "true"])
+        .status()
+        .unwrap();
+    mock_end.assert_calls(2);
+    assert!(status.success());
+}
+
+#[test]
+fn streams_output_before_exit() {
+    use std::io::{BufRead, BufReader};
+
+    let (server, sandbox) = setup_server(true);
+    let mut child = sandbox
+        .command()
+        .args([
+            "--slug=_",
+            "--url",
+            &server.url(""),
+            "bash",
+            "-c",
+            "echo foo; sleep 2",
+        ])
+        .stdout(process::Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let mut line = String::new();
+    BufReader::new(child.stdout.as_mut().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    assert_eq!(line.trim(), "foo");
+    assert!(child.try_wait().unwrap().is_none());
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+fn run_timeout_kills_command() {
+    let (server, sandbox) = setup_server(false);
+    let mock_end = server.mock(|when, then| {
+        when.method(POST)
+            .path_matches(Regex::from("/sleeper/124$"))
+            .body_includes("timed out");
+        then.status(200);
+    });
+
+    let status = sandbox
+        .command()
+        .args([
+            "--slug=sleeper",
+            "--url",
+            &server.url(""),
+            "--success-only",
+            "--run-timeout=1",
+            "sleep",
+            "10",
+        ])
+        .output()
+        .unwrap()
+        .status;
+    mock_end.assert_calls(0);
+    assert_eq!(status.code(), Some(124));
+
+    let status = sandbox
+        .command()
+        .args([
+            "--slug=sleeper",
+            "--url",
+            &server.url(""),
+            "--run-timeout=1",
+            "--kill-after=1",
+            "bash",
+            "-c",
+            "trap '' TERM; while true; do sleep 0.1; done",
+        ])
+        .output()
+        .unwrap()
+        .status;
+    mock_end.assert();
+    assert_eq!(status.code(), Some(124));
+}
+
+#[test]
+fn run_timeout_kills_process_group() {
+    let (server, sandbox) = setup_server(true);
+    let dir = tempdir().unwrap();
+    let marker = dir.path().join("survived");
+
+    // The shell forks rather than execs its last command, which holds the
+    // output pipes open
+    let start = std::time::Instant::now();
+    let status = sandbox
+        .command()
+        .args(["--slug=forking", "--url", &server.url("")])
+        .args(["--run-timeout=1", "--", "sh", "-c"])
+        .arg(format!("sleep 3; touch {}; :", marker.display()))
+        .output()
+        .unwrap()
+        .status;
+    assert_eq!(status.code(), Some(124));
+    assert!(start.elapsed() < std::time::Duration::from_secs(3));
+    std::thread::sleep(std::time::Duration::from_secs(3));
+    assert!(!marker.exists());
+
+    // Background processes left running don't hold up the end ping
+    let start = std::time::Instant::now();
+    let result = sandbox
+        .command()
+        .args(["--slug=forking", "--url", &server.url("")])
+        .args(["--", "sh", "-c", "sleep 10 & echo started"])
+        .output()
+        .unwrap();
+    assert!(result.status.success());
+    assert!(
+        str::from_utf8(&result.stdout)
+            .unwrap()
+            .starts_with("started\n")
+    );
+    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+}
+
+/// Open a pseudo-terminal, returning its controller and the path of the
+/// terminal itself
+fn open_pty() -> (fs::File, PathBuf) {
+    // SAFETY: the returned descriptor is owned by the `File`, and `ptsname`'s
+    // result is copied before any other call could overwrite it
+    unsafe {
+        let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+        assert!(fd >= 0, "{}", io::Error::last_os_error());
+        let controller = fs::File::from_raw_fd(fd);
+        assert_eq!(libc::grantpt(fd), 0);
+        assert_eq!(libc::unlockpt(fd), 0);
+        let name = libc::ptsname(fd);
+        assert!(!name.is_null());
+        let path = CStr::from_ptr(name).to_str().unwrap().into();
+        (controller, path)
+    }
+}
+
+#[test]
+fn reads_from_terminal() {
+    let (server, sandbox) = setup_server(true);
+    let (mut pty, tty) = open_pty();
+    let tty = fs::File::options()
+        .read(true)
+        .write(true)
+        .open(tty)
+        .unwrap();
+
+    let mut command = sandbox.command();
+    command
+        .args(["--slug=terminal", "--url", &server.url("")])
+        .args(["--", "sh", "-c", r#"read -r line; echo "read $line""#])
+        .stdin(tty)
+        .stdout(process::Stdio::piped());
+    // SAFETY: only async-signal-safe functions are called between `fork` and
+    // `exec`.
+    unsafe {
+        // Run in a new session whose controlling terminal is the pty, as in
+        // an interactive shell
+        command.pre_exec(|| {
+            if libc::setsid() == -1 || libc::ioctl(0, libc::TIOCSCTTY, 0) == -1
+            {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn().unwrap();
+    pty.write_all(b"hello\n").unwrap();
+
+    // A command that reads from the terminal must not be stopped
+    let start = std::time::Instant::now();
+    while child.try_wait().unwrap().is_none() {
+        if start.elapsed() > std::time::Duration::from_secs(10) {
+            child.kill().unwrap();
+            panic!("hc-runner didn't exit");
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    let result = child.wait_with_output().unwrap();
+    assert!(result.status.success());
+    assert!(
+        str::from_utf8(&result.stdout)
+            .unwrap()
+            .contains("read hello\n")
+    );
+}
+
+#[test]
+fn retries_server_errors() {
+    let (server, sandbox) = setup_server(false);
+    let mock_start = server.mock(|when, then| {
+        when.method(HEAD).path_matches(Regex::from("/flaky/start$"));
+        then.status(200);
+    });
+    let mock_end = server.mock(|when, then| {
+        when.method(POST).path_matches(Regex::from("/flaky/0$"));
+        then.status(503);
+    });
+
+    let result = sandbox
+        .command()
+        .args([
+            "--slug=flaky",
+            "--url",
+            &server.url(""),
+            "--retries=2",
+            "--retry-backoff=10",
+            "true",
+        ])
+        .output()
+        .unwrap();
+    mock_start.assert_calls(1);
+    mock_end.assert_calls(3);
+    assert!(result.status.success());
+    assert!(
+        str::from_utf8(&result.stdout)
+            .unwrap()
+            .contains("ping not delivered (server responded 503")
+    );
+}
+
+#[test]
+fn does_not_retry_client_errors() {
+    let (server, sandbox) = setup_server(false);
+    let mock_end = server.mock(|when, then| {
+        when.method(POST).path_matches(Regex::from("/missing/0$"));
+        then.status(404);
+    });
+
+    let status = sandbox
+        .command()
+        .args([
+            "--slug=missing",
+            "--url",
+            &server.url(""),
+            "--success-only",
+            "--retries=2",
+            "--retry-backoff=10",
+            "true",
+        ])
+        .output()
+        .unwrap()
+        .status;
+    mock_end.assert_calls(1);
+    assert!(status.success());
+}
+
+#[test]
+fn sends_run_id() {
+    let (server, sandbox) = setup_server(false);
+    let mock_start = server.mock(|when, then| {
+        when.method(HEAD)
+            .path_matches(Regex::from("/rid/start$"))
+            .query_param("create", "1")
+            .query_param_exists("rid");
+        then.status(200);
+    });
+    let mock_end = server.mock(|when, then| {
+        when.method(POST)
+            .path_matches(Regex::from("/rid/0$"))
+            .query_param_exists("rid");
+        then.status(200);
+    });
+
+    let result = sandbox
+        .command()
+        .args([
+            "--slug=rid",
+            "--url",
+            &server.url(""),
+            "bash",
+            "-c",
+            "echo -n $HC_RUNNER_RID",
+        ])
+        .output()
+        .unwrap();
+    mock_start.assert();
+    mock_end.assert();
+    assert!(result.status.success());
+
+    // The child's environment should contain the run ID
+    let rid = str::from_utf8(&result.stdout).unwrap();
+    assert_eq!(rid.len(), 36);
+    assert_eq!(rid.matches('-').count(), 4);
+}
+
+#[test]
+fn body_source_and_limit() {
+    let (server, sandbox) = setup_server(false);
+    let mock_both = server.mock(|when, then| {
+        when.method(POST)
+            .path_matches(Regex::from("/both/0$"))
+            .body("foo\nbar\n");
+        then.status(200);
+    });
+    let mock_truncated = server.mock(|when, then| {
+        when.method(POST)
+            .path_matches(Regex::from("/truncated/0$"))
+            .body_prefix("\n[... ")
+            .body_suffix("000\nxxx\n");
+        then.status(200);
+    });
+
+    let status = sandbox
+        .command()
+        .args([
+            "--slug=both",
+            "--url",
+            &server.url(""),
+            "--success-only",
+            "--body-source=both",
+            "bash",
+            "-c",
+            "echo foo; sleep 0.1; echo bar >&2",
+        ])
+        .output()
+        .unwrap()
+        .status;
+    mock_both.assert();
+    assert!(status.success());
+
+    let status = sandbox
+        .command()
+        .args([
+            "--slug=truncated",
+            "--url",
+            &server.url(""),
+            "--success-only",
+            "--body-source=stdout",
+            "--body-limit=50",
+            "--truncate=tail",
+            "bash",
+            "-c",
+            "seq 1000; echo xxx",
+        ])
+        .output()
+        .unwrap()
+        .status;
+    mock_truncated.assert();
+    assert!(status.success());
+}
+
+#[test]
+fn success_codes_report_success() {
+    let (server, sandbox) = setup_server(false);
+    let (mock_start, mock_end) = successful_run(&server, "vanished");
+
+    let status = sandbox
+        .command()
+        .args([
+            "--slug=vanished",
+            "--url",
+            &server.url(""),
+            "--success-codes=1,24",
+            "bash",
+            "-c",
+            "exit 24",
+        ])
+        .output()
+        .unwrap()
+        .status;
+    mock_start.assert_calls(1);
+    mock_end.assert_calls(1);
+    assert_eq!(status.code(), Some(24));
+
+    let status = sandbox
+        .command()
+        .args([
+            "--slug=vanished",
+            "--url",
+            &server.url(""),
+            "--success-codes=1,24",
+            "--normalize-exit-code",
+            "bash",
+            "-c",
+            "exit 24",
+        ])
+        .output()
+        .unwrap()
+        .status;
+    mock_end.assert_calls(2);
+    assert!(status.success());
+}
+
+#[test]
+fn pings_by_uuid() {
+    let uuid = "0b2a9e8c-1d6b-4a5e-9a57-3f4e2b1c0d9e";
+    let (server, sandbox) = setup_server(false);
+    let mock_start = server.mock(|when, then| {
+        when.method(HEAD)
+            .path(format!("/{uuid}/start"))
+            .query_param_missing("create");
+        then.status(200);
+    });
+    let mock_end = server.mock(|when, then| {
+        when.method(POST).path(format!("/{uuid}/0"));
+        then.status(200);
+    });
+
+    let status = sandbox
+        .command()
+        .args(["--uuid", uuid, "--url", &server.url(""), "true"])
+        .output()
+        .unwrap()
+        .status;
+    mock_start.assert();
+    mock_end.assert();
+    assert!(status.success());
+}
+
+#[test]
+fn forwards_progress_to_log() {
+    let (server, sandbox) = setup_server(false);
+    let (_, mock_end) = successful_run(&server, "progress");
+    let mock_log = server.mock(|when, then| {
+        when.method(POST)
+            .path_matches(Regex::from("/progress/log$"))
+            .query_param_exists("rid")
+            .body_includes("progress 2")
+            .body_excludes("other");
+        then.status(200);
+    });
+
+    let status = sandbox
+        .command()
+        .args([
+            "--slug=progress",
+            "--url",
+            &server.url(""),
+            "--log-pattern=^progress",
+            "--log-interval=60",
+            "bash",
+            "-c",
+            "echo progress 1; echo other; echo progress 2 >&2",
+        ])
+        .output()
+        .unwrap()
+        .status;
+    mock_end.assert();
+    mock_log.assert_calls(1);
+    assert!(status.success());
+
+    let mock_fd = server.mock(|when, then| {
+        when.method(POST)
+            .path_matches(Regex::from("/progress/log$"))
+            .body_includes("step 2\n")
+            .body_excludes("not a step");
+        then.status(200);
+    });
+    let status = sandbox
+        .command()
+        .args([
+            "--slug=progress",
+            "--url",
+            &server.url(""),
+            "--log-fd=3",
+            "--log-interval=60",
+            "bash",
+            "-c",
+            "echo step 1 >&3; echo step 2 >&3; echo not a step",
+        ])
+        .output()
+        .unwrap()
+        .status;
+    mock_fd.assert();
+    assert!(status.success());
+
+    // An outage doesn't slow down a command writing lots of progress
+    let start = std::time::Instant::now();
+    let status = sandbox
+        .command()
+        .args(["--slug=progress", "--url=http://127.0.0.1:1/"])
+        .args([
+            "--success-only",
+            "--retries=3",
+            "--log-fd=3",
+            "--log-interval=0",
+        ])
+        .args(["--", "sh", "-c", "seq 20000 >&3; exit 1"])
+        .output()
+        .unwrap()
+        .status;
+    assert_eq!(status.code(), Some(1));
+    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+}
+
+#[test]
+fn batch_runs_manifest() {
+    let (server, sandbox) = setup_server(false);
+    let (mock_start, mock_end) = successful_run(&server, "batch-ok");
+    let mock_fail = server.mock(|when, then| {
+        when.method(POST)
+            .path_matches(Regex::from("/batch-fail/3$"));
+        then.status(200);
+    });
+
+    let manifest = Builder::new().suffix(".toml").tempfile().unwrap();
+    fs::write(
+        manifest.path(),
+        r#"
+        concurrency = 2
+
+        [[checks]]
+        slug = "batch-ok"
+        command = ["true"]
+
+        [[checks]]
+        slug = "batch-fail"
+        command = ["bash", "-c", "exit 3"]
+        success_only = false
+        "#,
+    )
+    .unwrap();
+
+    let result = sandbox
+        .command()
+        .args([
+            "--url",
+            &server.url(""),
+            "batch",
+            manifest.path().to_str().unwrap(),
+        ])
+        .output()
+        .unwrap();
+    mock_start.assert();
+    mock_end.assert();
+    mock_fail.assert();
+    assert_eq!(result.status.code(), Some(1));
+
+    let stdout = str::from_utf8(&result.stdout).unwrap();
+    let mut lines = stdout.lines();
+    assert!(lines.next().unwrap().starts_with("CHECK"));
+    let ok = lines.next().unwrap();
+    assert!(ok.starts_with("batch-ok") && ok.contains(" 0 "));
+    let fail = lines.next().unwrap();
+    assert!(fail.starts_with("batch-fail") && fail.contains(" 3 "));
+
+    // The URL is only looked up once for the whole batch
+    let dir = tempdir().unwrap();
+    let counter = dir.path().join("counter");
+    let command =
+        format!("echo >> {}; echo {}", counter.display(), server.url(""));
+    let result = sandbox
+        .command()
+        .args(["--url-command", &command, "batch"])
+        .arg(manifest.path())
+        .output()
+        .unwrap();
+    assert_eq!(result.status.code(), Some(1));
+    mock_end.assert_calls(2);
+    assert_eq!(fs::read_to_string(&counter).unwrap(), "\n");
+
+    // Flags for a single check aren't valid in batch mode
+    let status = sandbox
+        .command()
+        .args([
+            "--url",
+            &server.url(""),
+            "--slug=foo",
+            "batch",
+            manifest.path().to_str().unwrap(),
+        ])
+        .output()
+        .unwrap()
+        .status;
+    assert!(!status.success());
+}
+
+#[test]
+fn runs_command_in_shell() {
+    let (server, sandbox) = setup_server(false);
+    let mock_end = server.mock(|when, then| {
+        when.method(POST).path_matches(Regex::from("/shell/0$"));
+        then.status(200);
+    });
+    let mock_fail = server.mock(|when, then| {
+        when.method(POST).path_matches(Regex::from("/shell/1$"));
+        then.status(200);
+    });
+
+    let result = sandbox
+        .command()
+        .args([
+            "--slug=shell",
+            "--url",
+            &server.url(""),
+            "--shell",
+            "--",
+            "echo foo bar | tr a-z A-Z",
+            "&& echo baz",
+        ])
+        .output()
+        .unwrap();
+    mock_end.assert();
+    assert_eq!(str::from_utf8(&result.stdout).unwrap(), "FOO BAR\nbaz\n");
+    assert!(result.status.success());
+
+    // Without pipefail the status of the pipeline is that of `cat`
+    let pipeline = ["false | cat"];
+    let status = sandbox
+        .command()
+        .args(["--slug=shell", "--url", &server.url(""), "--shell", "--"])
+        .args(pipeline)
+        .output()
+        .unwrap()
+        .status;
+    mock_end.assert_calls(2);
+    assert!(status.success());
+
+    let status = sandbox
+        .command()
+        .args([
+            "--slug=shell",
+            "--url",
+            &server.url(""),
+            "--shell",
+            "--shell-path=/bin/bash",
+            "--pipefail",
+            "--",
+        ])
+        .args(pipeline)
+        .output()
+        .unwrap()
+        .status;
+    mock_fail.assert();
+    assert_eq!(status.code(), Some(1));
+}
+
+#[test]
+fn lock_prevents_overlap() {
+    let (server, sandbox) = setup_server(false);
+    let mock_start = server.mock(|when, then| {
+        when.method(HEAD)
+            .path_matches(Regex::from("/locked/start$"));
+        then.status(200);
+    });
+    let mock_end = server.mock(|when, then| {
+        when.method(POST).path_matches(Regex::from("/locked/0$"));
+        then.status(200);
+    });
+    let mock_log = server.mock(|when, then| {
+        when.method(POST)
+            .path_matches(Regex::from("/locked/log$"))
+            .body_includes("still in progress");
+        then.status(200);
+    });
+    let mock_fail = server.mock(|when, then| {
+        when.method(POST)
+            .path_matches(Regex::from("/locked/fail$"))
+            .body_includes("still in progress");
+        then.status(200);
+    });
+
+    let hc_runner = |policy: &str, cmd: &[&str]| {
+        let mut command = sandbox.command();
+        command
+            .args(["--slug=locked", "--url", &server.url("")])
+            .arg(format!("--lock={policy}"))
+            .args(["--lock-wait=1", "--"])
+            .args(cmd);
+        command
+    };
+
+    let mut holder = hc_runner("fail", &["sleep", "3"]).spawn().unwrap();
+    while mock_start.calls() == 0 {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+
+    let status = hc_runner("skip", &["true"]).status().unwrap();
+    assert!(status.success());
+
+    let status = hc_runner("skip-log", &["true"]).status().unwrap();
+    assert!(status.success());
+    mock_log.assert();
+
+    let status = hc_runner("wait", &["true"]).status().unwrap();
+    assert_eq!(status.code(), Some(75));
+    let status = hc_runner("fail", &["true"]).status().unwrap();
+    assert_eq!(status.code(), Some(75));
+    // Failures aren't reported with `--success-only`
+    let status = hc_runner("fail", &["true"])
+        .env("HC_RUNNER_SUCCESS_ONLY", "true")
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(75));
+    mock_fail.assert_calls(2);
+    mock_start.assert();
+
+    assert!(holder.wait().unwrap().success());
+    mock_end.assert();
+
+    // The lock is released once the previous run finishes
+    let status = hc_runner("fail", &["true"]).status().unwrap();
+    assert!(status.success());
+    mock_end.assert_calls(2);
+
+    // Runs that couldn't take the lock are recorded too
+    let result = sandbox
+        .command()
+        .args(["history", "--failed", "--json"])
+        .output()
+        .unwrap();
+    let stdout = str::from_utf8(&result.stdout).unwrap();
+    assert_eq!(stdout.matches(r#""exit_code": 75"#).count(), 3);
+    assert!(stdout.contains(r#""ping": "skipped""#));
+}
+
+#[test]
+fn reports_and_forwards_signals() {
+    let (server, sandbox) = setup_server(false);
+    let mock_end = server.mock(|when, then| {
+        when.method(POST)
+            .path_matches(Regex::from("/signaled/143$"))
+            .body_includes("killed by SIGTERM");
+        then.status(200);
+    });
+    let mock_hup = server.mock(|when, then| {
+        when.method(POST)
+            .path_matches(Regex::from("/signaled/129$"))
+            .body_includes("killed by SIGHUP");
+        then.status(200);
+    });
+
+    let status = sandbox
+        .command()
+        .args(["--slug=signaled", "--url", &server.url("")])
+        .args(["--", "sh", "-c", "kill -TERM $$"])
+        .output()
+        .unwrap()
+        .status;
+    mock_end.assert();
+    assert_eq!(status.code(), Some(143));
+
+    // Signals sent to hc-runner are passed on to the command
+    let mut child = sandbox
+        .command()
+        .args(["--slug=signaled", "--url", &server.url("")])
+        .args(["--", "sh", "-c", "echo ready; exec sleep 10"])
+        .stdout(process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut line = String::new();
+    std::io::BufRead::read_line(
+        &mut std::io::BufReader::new(child.stdout.take().unwrap()),
+        &mut line,
+    )
+    .unwrap();
+    assert_eq!(line, "ready\n");
+
+    let status = process::Command::new("kill")
+        .args(["-HUP", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(child.wait().unwrap().code(), Some(129));
+    mock_hup.assert();
+
+    // Checks that haven't started yet are skipped
+    let manifest = Builder::new().suffix(".toml").tempfile().unwrap();
+    fs::write(
+        manifest.path(),
+        r#"
+        concurrency = 1
+
+        [[checks]]
+        slug = "signaled"
+        command = ["sh", "-c", "echo ready; exec sleep 10"]
+
+        [[checks]]
+        slug = "queued"
+        command = ["echo", "queued started"]
+        "#,
+    )
+    .unwrap();
+    let mut child = sandbox
+        .command()
+        .args(["--url", &server.url(""), "batch"])
+        .arg(manifest.path())
+        .stdout(process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdout = std::io::BufReader::new(child.stdout.take().unwrap());
+    let mut line = String::new();
+    std::io::BufRead::read_line(&mut stdout, &mut line).unwrap();
+    assert_eq!(line, "ready\n");
+
+    let status = process::Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(child.wait().unwrap().code(), Some(1));
+    let mut rest = String::new();
+    std::io::Read::read_to_string(&mut stdout, &mut rest).unwrap();
+    assert!(!rest.contains("queued started"));
+    assert!(
+        rest.lines()
+            .any(|line| line.split_whitespace().eq(["queued", "-", "0.0s"]))
+    );
+    mock_end.assert_calls(2);
+}
+
+#[test]
+fn reports_spawn_failures() {
+    let (server, sandbox) = setup_server(false);
+    let (mock_start, _) = successful_run(&server, "unspawned");
+    let mock_fail = server.mock(|when, then| {
+        when.method(POST)
+            .path_matches(Regex::from("/unspawned/fail$"))
+            .body_includes("failed to run `hc-runner-does-not-exist`");
+        then.status(200);
+    });
+    let mock_code = server.mock(|when, then| {
+        when.method(POST)
+            .path_matches(Regex::from("/unspawned/99$"))
+            .body_includes("Permission denied");
+        then.status(200);
+    });
+
+    let result = sandbox
+        .command()
+        .args(["--slug=unspawned", "--url", &server.url("")])
+        .arg("hc-runner-does-not-exist")
+        .output()
+        .unwrap();
+    mock_start.assert();
+    mock_fail.assert();
+    assert_eq!(result.status.code(), Some(127));
+    assert!(
+        str::from_utf8(&result.stderr)
+            .unwrap()
+            .contains("failed to run `hc-runner-does-not-exist`")
+    );
+
+    // Not executable
+    let script = Builder::new().tempfile().unwrap();
+    let status = sandbox
+        .command()
+        .args(["--slug=unspawned", "--url", &server.url("")])
+        .arg("--spawn-failure-code=99")
+        .arg(script.path())
+        .output()
+        .unwrap()
+        .status;
+    mock_code.assert();
+    assert_eq!(status.code(), Some(126));
+}
+
+#[test]
+fn json_body_format() {
+    let (server, sandbox) = setup_server(false);
+    let mock_end = server.mock(|when, then| {
+        when.method(POST)
+            .path_matches(Regex::from("/json/3$"))
+            .body_includes(r#""hc_runner_version": ""#)
+            .body_includes(r#""hostname": ""#)
+            .body_includes(r#""exit_code": 3"#)
+            .body_includes(r#""signal": null"#)
+            .body_includes(r#""max_rss": "#)
+            .body_includes(r#""stdout": "foo\n""#)
+            .body_includes(r#""stderr": "bar\n""#);
+        then.status(200);
+    });
+
+    let status = sandbox
+        .command()
+        .args(["--slug=json", "--url", &server.url("")])
+        .args(["--body-format=json", "--body-source=both", "--"])
+        .args(["sh", "-c", "echo foo; echo bar >&2; exit 3"])
+        .output()
+        .unwrap()
+        .status;
+    mock_end.assert();
+    assert_eq!(status.code(), Some(3));
+}
+
+#[test]
+fn records_history() {
+    let (server, sandbox) = setup_server(true);
+    let hc_runner = || sandbox.command();
+
+    for (slug, cmd) in [("good", "true"), ("bad", "false"), ("good", "true")] {
+        hc_runner()
+            .args(["--slug", slug, "--url", &server.url(""), cmd])
+            .status()
+            .unwrap();
+    }
+    // Commands that can't be started are recorded too
+    hc_runner()
+        .args(["--slug=missing", "--url", &server.url("")])
+        .arg("hc-runner-test-does-not-exist")
+        .status()
+        .unwrap();
+    // Disabled with a limit of 0
+    hc_runner()
+        .args(["--slug=unrecorded", "--url", &server.url("")])
+        .args(["--history-limit=0", "true"])
+        .status()
+        .unwrap();
+
+    let result = hc_runner().arg("history").output().unwrap();
+    assert!(result.status.success());
+    let stdout = str::from_utf8(&result.stdout).unwrap();
+    let lines: Vec<_> = stdout.lines().collect();
+    assert_eq!(lines.len(), 5);
+    assert!(lines[0].starts_with("START"));
+    assert!(lines[2].contains("  bad  "));
+    assert!(lines[2].ends_with("sent"));
+    assert!(lines[4].contains("  missing     127  "));
+    assert!(!stdout.contains("unrecorded"));
+
+    let result = hc_runner()
+        .args(["history", "--failed", "--json"])
+        .output()
+        .unwrap();
+    let stdout = str::from_utf8(&result.stdout).unwrap();
+    assert!(stdout.contains(r#""check": "bad""#));
+    assert!(stdout.contains(r#""exit_code": 1"#));
+    assert!(!stdout.contains(r#""check": "good""#));
+
+    let result = hc_runner()
+        .args(["history", "--slug=good", "-n1"])
+        .output()
+        .unwrap();
+    assert_eq!(str::from_utf8(&result.stdout).unwrap().lines().count(), 2);
+}
+
+#[test]
+fn spools_and_flushes_pings() {
+    let (server, sandbox) = setup_server(false);
+    let mut mock_down = server.mock(|when, then| {
+        when.method(POST).path_matches(Regex::from("/spooled/0$"));
+        then.status(503);
+    });
+    let hc_runner = || sandbox.command();
+
+    let status = hc_runner()
+        .args(["--slug=spooled", "--url", &server.url("/secretpingkey")])
+        .args(["--body-source=stdout", "echo", "spool me"])
+        .status()
+        .unwrap();
+    mock_down.assert();
+    assert!(status.success());
+    let spool = sandbox.data_dir().join("hc-runner").join("spool");
+    let spooled = || {
+        fs::read_dir(&spool)
+            .unwrap()
+            .filter(|entry| {
+                let path = entry.as_ref().unwrap().path();
+                path.extension().is_some_and(|ext| ext == "json")
+            })
+            .count()
+    };
+    assert_eq!(spooled(), 1);
+
+    let result = hc_runner().args(["-vv", "flush"]).output().unwrap();
+    assert_eq!(result.status.code(), Some(1));
+    mock_down.assert_calls(2);
+    // The ping key is masked although `flush` isn't given the URL
+    let stdout = str::from_utf8(&result.stdout).unwrap();
+    assert!(stdout.contains("/********/spooled/0?rid="));
+    assert!(!stdout.contains("secretpingkey"));
+    mock_down.delete();
+
+    let mock_up = server.mock(|when, then| {
+        when.method(POST)
+            .path_matches(Regex::from("/spooled/0$"))
+            .query_param_exists("rid")
+            .body_includes("spool me\nhc-runner: ping spooled at ");
+        then.status(200);
+    });
+    let result = hc_runner().arg("flush").output().unwrap();
+    assert!(result.status.success());
+    assert_eq!(
+        str::from_utf8(&result.stdout).unwrap(),
+        "sent 1 spooled pings, 0 remaining\n"
+    );
+    mock_up.assert();
+    assert_eq!(spooled(), 0);
+
+    // An unreachable server doesn't change the outcome of the run
+    let result = hc_runner()
+        .args(["--slug=spooled", "--url=http://127.0.0.1:1/"])
+        .args(["--", "sh", "-c", "exit 3"])
+        .output()
+        .unwrap();
+    assert_eq!(result.status.code(), Some(3));
+    assert!(result.stderr.is_empty());
+    assert_eq!(spooled(), 1);
+}
+
+#[test]
+fn dry_run_makes_no_requests() {
+    let (server, sandbox) = setup_server(false);
+    let mock = server.mock(|when, then| {
+        when.any_request();
+        then.status(200);
+    });
+    let dir = tempdir().unwrap();
+    let marker = dir.path().join("ran");
+
+    let result = sandbox
+        .command()
+        .env("HC_RUNNER_TIMEOUT", "7")
+        .args(["--dry-run", "--slug=dry", "--retries=2", "--url"])
+        .arg(server.url("/secretpingkey"))
+        .args(["touch", marker.to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert!(result.status.success());
+    mock.assert_calls(0);
+    assert!(!marker.exists());
+
+    let stdout = str::from_utf8(&result.stdout).unwrap();
+    assert!(!stdout.contains("secretpingkey"));
+    let line = |key: &str| {
+        stdout
+            .lines()
+            .find(|line| line.starts_with(&format!("{key} ")))
+            .unwrap()
+            .split_whitespace()
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(line("timeout"), ["timeout", "7", "(environment)"]);
+    assert_eq!(line("retries"), ["retries", "2", "(command", "line)"]);
+    assert_eq!(line("body_limit"), ["body_limit", "100000", "(default)"]);
+    assert!(line("url")[1].ends_with("/********"));
+    assert!(stdout.contains("/********/dry/start?rid="));
+}
+
+#[test]
+fn redacts_ping_key() {
+    let (server, sandbox) = setup_server(true);
+    let url = server.url("/secretpingkey");
+    let result = sandbox
+        .command()
+        .args(["-vv", "--slug=redacted", "--url", &url, "true"])
+        .output()
+        .unwrap();
+    assert!(result.status.success());
+    let stdout = str::from_utf8(&result.stdout).unwrap();
+    assert!(!stdout.contains("secretpingkey"));
+    assert!(stdout.contains("/********/redacted/start?rid="));
+
+    let result = sandbox
+        .command()
+        .args(["-vv", "--show-secrets", "--slug=redacted", "--url", &url])
+        .arg("true")
+        .output()
+        .unwrap();
+    let stdout = str::from_utf8(&result.stdout).unwrap();
+    assert!(stdout.contains("/secretpingkey/redacted/start?rid="));
+
+    // Errors include the URL of the failed request
+    let uuid = "0b2a9e8c-1d6b-4a5e-9a57-3f4e2b1c0d9e";
+    let result = sandbox
+        .command()
+        .args(["--url=http://127.0.0.1:1/", "--uuid", uuid, "true"])
+        .output()
+        .unwrap();
+    assert!(result.status.success());
+    let stdout = str::from_utf8(&result.stdout).unwrap();
+    assert!(stdout.contains("http://127.0.0.1:1/********/0?rid="));
+    assert!(!stdout.contains(uuid));
+}
+
+#[test]
+fn reads_url_from_command() {
+    let (server, sandbox) = setup_server(false);
+    let mock = server.mock(|when, then| {
+        when.method(POST).path("/from-command/cmd/0");
+        then.status(200);
+    });
+    let command = format!("echo {}", server.url("/from-command"));
+    let status = sandbox
+        .command()
+        .args(["--slug=cmd", "--url-command", &command, "true"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    mock.assert();
+}
+
+#[test]
+fn config_subcommand() {
+    let sandbox = Sandbox::new();
+    let hc_runner = || sandbox.command();
+    let path = sandbox.home().join(".config/hc-runner/config.toml");
+
+    let result = hc_runner().args(["config", "path"]).output().unwrap();
+    assert!(str::from_utf8(&result.stdout).unwrap().contains(
+        ".config/hc-runner/config.{toml,yaml,yml,json} (not found)"
+    ));
+
+    let status = hc_runner().args(["config", "init"]).status().unwrap();
+    assert!(status.success());
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    let status = hc_runner().args(["config", "init"]).status().unwrap();
+    assert!(!status.success());
+    // The template can only be written as TOML
+    let json = sandbox.home().join("config.json");
+    let status = hc_runner()
+        .args(["--config", json.to_str().unwrap(), "config", "init"])
+        .status()
+        .unwrap();
+    assert!(!status.success());
+    assert!(!json.exists());
+
+    let result = hc_runner().args(["config", "check"]).output().unwrap();
+    assert!(result.status.success());
+
+    fs::write(
+        &path,
+        "url = \"https://hc-ping.com/secretkey/\"\nretires = 5\n",
+    )
+    .unwrap();
+    let result = hc_runner().args(["config", "check"]).output().unwrap();
+    assert_eq!(result.status.code(), Some(1));
+    assert!(
+        str::from_utf8(&result.stdout)
+            .unwrap()
+            .contains("unknown key `retires`")
+    );
+
+    let result = hc_runner()
+        .env("HC_RUNNER_TIMEOUT", "4")
+        .args(["config", "show"])
+        .output()
+        .unwrap();
+    let stdout = str::from_utf8(&result.stdout).unwrap();
+    assert!(!stdout.contains("secretkey"));
+    assert!(stdout.contains("https://hc-ping.com/********/"));
+    assert!(stdout.lines().any(|line| line.split_whitespace().eq([
+        "timeout",
+        "4",
+        "(environment)"
+    ])));
 }